@@ -0,0 +1,99 @@
+// Re-encodes the UTF-8 text written to it into another encoding, used by --output-encoding
+
+use std::io::{self, Write};
+
+use encoding_rs::{EncoderResult, Encoding};
+
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    encoding: &'static Encoding,
+    pending: Vec<u8>, // Bytes of a UTF-8 character split across two writes
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, encoding: &'static Encoding) -> Self {
+        Self { inner, encoding, pending: Vec::new() }
+    }
+}
+
+// Encodes text, writing '?' for characters the target encoding can't represent
+pub fn encode_lossy(encoding: &'static Encoding, text: &str) -> Vec<u8> {
+    let mut encoder = encoding.new_encoder();
+    let mut encoded = Vec::with_capacity(text.len());
+    let mut rest = text;
+
+    loop {
+        let capacity = encoder
+            .max_buffer_length_from_utf8_without_replacement(rest.len())
+            .unwrap_or(rest.len() * 4)
+            .max(16);
+        let mut buffer = vec![0; capacity];
+        let (result, read, written) = encoder.encode_from_utf8_without_replacement(rest, &mut buffer, true);
+        encoded.extend_from_slice(&buffer[..written]);
+        rest = &rest[read..];
+        match result {
+            EncoderResult::InputEmpty => break,
+            EncoderResult::Unmappable(_) => encoded.push(b'?'),
+            EncoderResult::OutputFull => {}
+        }
+    }
+
+    encoded
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(), // Character cut off at the end
+            Err(err) => {
+                // Drop the bad bytes, or every later write would trip over them again
+                self.pending.clear();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        };
+
+        let text = std::str::from_utf8(&self.pending[..valid]).expect("checked above");
+        self.inner.write_all(&encode_lossy(self.encoding, text))?;
+        self.pending.drain(..valid);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latin1_round_trip() {
+        let mut writer = EncodingWriter::new(Vec::new(), encoding_rs::WINDOWS_1252);
+        let text = "plain café naïve";
+        // Split inside the é so the writer has to hold on to half a character
+        let split = text.find('é').unwrap() + 1;
+        writer.write_all(&text.as_bytes()[..split]).unwrap();
+        writer.write_all(&text.as_bytes()[split..]).unwrap();
+
+        assert_eq!(writer.inner, b"plain caf\xe9 na\xefve");
+        let (decoded, _, had_errors) = encoding_rs::WINDOWS_1252.decode(&writer.inner);
+        assert!(!had_errors);
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn unmappable_characters_become_question_marks() {
+        assert_eq!(encode_lossy(encoding_rs::WINDOWS_1252, "a→b"), b"a?b");
+    }
+
+    #[test]
+    fn recovers_after_invalid_utf8() {
+        let mut writer = EncodingWriter::new(Vec::new(), encoding_rs::WINDOWS_1252);
+        assert!(writer.write(b"\xff\xfe").is_err());
+        writer.write_all(b"ok").unwrap();
+        assert_eq!(writer.inner, b"ok");
+    }
+}
@@ -1,95 +1,2918 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::error::Error;
-use std::{fs, result};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+mod encoding;
+pub mod regions;
+
+use encoding::EncodingWriter;
+use encoding_rs::Encoding;
+use regex::{Regex, RegexBuilder};
+use regions::Region;
+use serde::Serialize;
 
 const RED: &str = "\x1b[31m";    // Red text
 const UNDERLINE: &str = "\x1b[4m"; // Underline
+const BOLD: &str = "\x1b[1m";      // Bold
+const REVERSE: &str = "\x1b[7m";   // Swap foreground and background
+const RED_BACKGROUND: &str = "\x1b[41m"; // Red background
+const CYAN: &str = "\x1b[36m";   // Cyan text, used for line numbers
+const MAGENTA: &str = "\x1b[35m"; // Magenta text, used for file paths
 const RESET: &str = "\x1b[0m";    // Reset all formatting
 
+// How a match is emphasized, chosen with --highlight-style
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HighlightStyle {
+    Underline,
+    Background,
+    Bold,
+    Reverse,
+}
+
+impl HighlightStyle {
+    fn parse(name: &str) -> Result<HighlightStyle, &'static str> {
+        match name {
+            "underline" => Ok(HighlightStyle::Underline),
+            "background" => Ok(HighlightStyle::Background),
+            "bold" => Ok(HighlightStyle::Bold),
+            "reverse" => Ok(HighlightStyle::Reverse),
+            _ => Err("unknown highlight style, expected underline, background, bold or reverse"),
+        }
+    }
+
+    // Without --highlight-color matches are red, and reverse video keeps the terminal's colors
+    fn codes(self, color: Option<HighlightColor>) -> String {
+        let chosen = color.unwrap_or(HighlightColor::Red);
+        match self {
+            HighlightStyle::Underline => format!("{}{}", chosen.foreground(), UNDERLINE),
+            HighlightStyle::Background => chosen.background().to_string(),
+            HighlightStyle::Bold => format!("{}{}", chosen.foreground(), BOLD),
+            HighlightStyle::Reverse => match color {
+                Some(color) => format!("{}{}", color.foreground(), REVERSE),
+                None => REVERSE.to_string(),
+            },
+        }
+    }
+}
+
+// The color a match is emphasized with, chosen with --highlight-color
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HighlightColor {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+}
+
+impl HighlightColor {
+    fn parse(name: &str) -> Result<HighlightColor, &'static str> {
+        match name {
+            "red" => Ok(HighlightColor::Red),
+            "green" => Ok(HighlightColor::Green),
+            "yellow" => Ok(HighlightColor::Yellow),
+            "blue" => Ok(HighlightColor::Blue),
+            "magenta" => Ok(HighlightColor::Magenta),
+            "cyan" => Ok(HighlightColor::Cyan),
+            _ => Err("unknown highlight color, expected red, green, yellow, blue, magenta or cyan"),
+        }
+    }
+
+    fn foreground(self) -> &'static str {
+        match self {
+            HighlightColor::Red => RED,
+            HighlightColor::Green => "\x1b[32m",
+            HighlightColor::Yellow => "\x1b[33m",
+            HighlightColor::Blue => "\x1b[34m",
+            HighlightColor::Magenta => MAGENTA,
+            HighlightColor::Cyan => CYAN,
+        }
+    }
+
+    fn background(self) -> &'static str {
+        match self {
+            HighlightColor::Red => RED_BACKGROUND,
+            HighlightColor::Green => "\x1b[42m",
+            HighlightColor::Yellow => "\x1b[43m",
+            HighlightColor::Blue => "\x1b[44m",
+            HighlightColor::Magenta => "\x1b[45m",
+            HighlightColor::Cyan => "\x1b[46m",
+        }
+    }
+}
+
+// The order -r visits files in, chosen with --traversal
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Traversal {
+    DepthFirst,
+    BreadthFirst,
+}
+
+impl Traversal {
+    fn parse(name: &str) -> Result<Traversal, &'static str> {
+        match name {
+            "dfs" => Ok(Traversal::DepthFirst),
+            "bfs" => Ok(Traversal::BreadthFirst),
+            _ => Err("unknown traversal, expected dfs or bfs"),
+        }
+    }
+}
+
+// When to color the output, chosen with --color
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn parse(name: &str) -> Result<ColorChoice, &'static str> {
+        match name {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err("unknown color choice, expected never, always or auto"),
+        }
+    }
+
+    // Auto colors only a terminal, and only when NO_COLOR isn't set
+    fn resolve(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                !no_color && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     query: String,
-    file_path: String,
+    file_paths: Vec<String>,
     ignore_case: bool,
+    count_exit: bool,
+    in_comments: bool,
+    in_strings: bool,
+    json_array: bool,
+    context_chars: Option<usize>,
+    emit_to: Option<String>,
+    summarize_after: Option<usize>,
+    highlight_style: HighlightStyle,
+    highlight_color: Option<HighlightColor>,
+    paragraph: bool,
+    csv: bool,
+    no_header: bool,
+    offset_histogram: bool,
+    output_encoding: Option<&'static Encoding>, // None keeps the output UTF-8
+    line_number: bool,
+    regex: bool,
+    recursive: bool,
+    count: bool,
+    invert: bool,
+    whole_word: bool,
+    before: usize, // Context lines printed before each match
+    after: usize,  // Context lines printed after each match
+    context_merge: usize, // Context groups fewer than this many lines apart are joined
+    color: bool,   // Resolved from --color and NO_COLOR
+    only_matching: bool,
+    max_count: Option<usize>,
+    json: bool,
+    files_with_matches: bool,
+    files_without_match: bool,
+    stats: bool,
+    resolve_symlinks: bool, // Print the real location of every file instead of the path it was found by
+    text_if_printable: Option<f64>, // Search binary-looking files anyway when more than this share is printable
+    perm_match: Option<u32>, // Only files -r finds with exactly these permission bits
+    owner: Option<u32>,      // Only files -r finds owned by this user id
+    traversal: Traversal,
+    validate_regex: bool, // Only compile the query, implies --regex
+    min_matches: usize,   // Files with fewer matching lines print nothing
+    max_matches_per_file: Option<usize>, // Files with more matching lines print nothing
+    density: bool, // --stats also lists each file's matches per KiB
+    keep_going: bool, // A file whose search panics is reported and skipped
+    time_budget: Option<Duration>, // No more files are searched once this much time has passed
+    rank: bool,          // Files are counted like with -c, then printed by score, see Sink::Ranked
+    recency_weight: f64, // How much a day of age lowers a file's --rank score
+    checkpoint: Option<String>,    // Where the files searched so far are recorded
+    continue_from: Option<String>, // A checkpoint whose files are skipped
+}
+
+const STDIN_LABEL: &str = "(standard input)"; // Path reported for matches read from stdin
+
+#[derive(Clone, Serialize)]
+struct MatchRecord {
+    path: String,
+    line_number: usize,
+    line: String,
+}
+
+// One --json line: a match record plus the byte offsets of every match within the line
+#[derive(Serialize)]
+struct JsonRecord {
+    path: String,
+    line_number: usize,
+    line: String,
+    matches: Vec<ByteRange>,
+}
+
+#[derive(Serialize)]
+struct ByteRange {
+    start: usize,
+    end: usize,
+}
+
+// A line picked by a search, with the byte ranges of its matches that get printed and
+// highlighted. Inverted matches have no ranges.
+struct LineMatch<'a> {
+    line_number: usize, // 1-based
+    line: &'a str,
+    ranges: Vec<(usize, usize)>,
 }
 
-fn normal_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+impl<'a> LineMatch<'a> {
+    fn new(pattern: &Pattern, invert: bool, line_number: usize, line: &'a str) -> LineMatch<'a> {
+        let ranges = if invert { Vec::new() } else { pattern.ranges(line) };
+        LineMatch { line_number, line, ranges }
+    }
+}
+
+// The search functions return each matching line with its 1-based line number.
+// With `invert` set they return the lines that don't match instead, and they stop
+// reading once `limit` lines matched.
+fn line_search<'a>(pattern: &Pattern, contents: &'a str, invert: bool, limit: usize) -> Vec<LineMatch<'a>> {
     let mut results = Vec::new();
 
-    for line in contents.lines() {
-        if line.contains(query) {
-            results.push(line);
+    for (i, line) in contents.lines().enumerate() {
+        if results.len() == limit {
+            break;
+        }
+        if pattern.is_match(line) != invert {
+            results.push(LineMatch::new(pattern, invert, i + 1, line));
         }
     }
 
     results
 }
 
+// Matches only inside the wanted comment/string regions of each line, so the ranges leave
+// out any match in the rest of the line
+fn region_search<'a>(
+    pattern: &Pattern,
+    contents: &'a str,
+    syntax: &regions::Syntax,
+    wanted: &[Region],
+    invert: bool,
+    limit: usize,
+) -> Vec<LineMatch<'a>> {
+    let mut results = Vec::new();
+
+    let lines = contents.lines().zip(regions::split_regions(contents, syntax));
+    for (i, (line, segments)) in lines.enumerate() {
+        if results.len() == limit {
+            break;
+        }
+        let wanted_segments = segments.iter().filter(|(region, _)| wanted.contains(region));
+        if wanted_segments.clone().any(|(_, text)| pattern.is_match(text)) == invert {
+            continue;
+        }
+        let mut ranges = Vec::new();
+        if !invert {
+            for (_, text) in wanted_segments {
+                // Segments are slices of the line, so their offset maps the ranges back onto it
+                let offset = text.as_ptr() as usize - line.as_ptr() as usize;
+                ranges.extend(pattern.ranges(text).into_iter().map(|(start, end)| (offset + start, offset + end)));
+            }
+        }
+        results.push(LineMatch { line_number: i + 1, line, ranges });
+    }
+
+    results
+}
 
-fn ignore_case_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+// Treats blank-line-separated blocks as records, returning each matching block
+// with the 1-based line number it starts on
+fn paragraph_search<'a>(pattern: &Pattern, contents: &'a str, invert: bool) -> Vec<(usize, Vec<&'a str>)> {
     let mut results = Vec::new();
-    let query = query.to_lowercase();
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line);
+    let mut block: Vec<&str> = Vec::new();
+    let mut block_start = 1;
+
+    // The trailing empty line flushes the last block
+    for (i, line) in contents.lines().chain([""]).enumerate() {
+        if !line.trim().is_empty() {
+            if block.is_empty() {
+                block_start = i + 1;
+            }
+            block.push(line);
+            continue;
+        }
+        if block.is_empty() {
+            continue;
+        }
+        if pattern.is_match(&block.join("\n")) != invert {
+            results.push((block_start, std::mem::take(&mut block)));
+        } else {
+            block.clear();
         }
     }
 
     results
 }
 
-impl Config {
-    pub fn build(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 5 {
-            return Err("not enough arguments");
+/// A matching line found by [`search`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    /// 1-based line number in the searched contents.
+    pub line_number: usize,
+    /// The whole matching line, without its line ending.
+    pub line: String,
+    /// Byte ranges of every non-overlapping match within `line`, left to right.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// Finds every line of `contents` containing `query`, optionally ignoring case.
+///
+/// # Examples
+///
+/// ```
+/// use minigrep::{search, Match};
+///
+/// let contents = "Rust:\nsafe, fast, productive.\nTrust me, it's fast.";
+/// assert_eq!(
+///     search("fast", contents, false),
+///     vec![
+///         Match { line_number: 2, line: "safe, fast, productive.".to_string(), ranges: vec![(6, 10)] },
+///         Match { line_number: 3, line: "Trust me, it's fast.".to_string(), ranges: vec![(15, 19)] },
+///     ]
+/// );
+/// assert_eq!(search("rust", contents, true)[1].ranges, vec![(1, 5)]);
+/// ```
+pub fn search(query: &str, contents: &str, ignore_case: bool) -> Vec<Match> {
+    let pattern = Pattern::Literal { query, ignore_case, whole_word: false };
+    line_search(&pattern, contents, false, usize::MAX)
+        .into_iter()
+        .map(|found| Match { line_number: found.line_number, line: found.line.to_string(), ranges: found.ranges })
+        .collect()
+}
+
+/// The usage message printed for `-h`/`--help`.
+pub const USAGE: &str = "\
+Usage: miniGrep -q <QUERY> [-p <PATH>...] [OPTIONS]
+       miniGrep [DIR] [--icons]    (opens the file browser)
+
+Searches the files after -p (or standard input) for lines containing QUERY.
+Short flags can be bundled, e.g. -in for -i -n or -iq foo for -i -q foo.
+
+Options:
+  -q <QUERY>, -q=<QUERY>       The text to search for
+  -p <PATH>..., -p=<PATH>...   Files to search, - for standard input
+  -r, --recursive              Search every file under the -p directories
+  -i, --ignore-case            Ignore case when matching, also set by IGNORE_CASE
+  -S, --smart-case             Ignore case unless the query has an uppercase letter
+  --regex                      Treat the query as a regular expression
+  --validate-regex             Only check that the query is a valid regex, exit 0 if so
+  -F, --fixed-strings          Treat the query as plain text (the default)
+  -w, --word-regexp            Only match whole words
+  -v, --invert-match           Print the lines that don't match
+  -n, --line-number            Prefix lines with their line number
+  -c, --count                  Print only the number of matching lines
+  --rank                       Print files by a score of matching lines and file age
+  --recency-weight <W>         How much each day of age lowers a --rank score (default 1)
+  -l, --files-with-matches     Print only the paths of matching files
+  -L, --files-without-match    Print only the paths of files without a match
+  -o, --only-matching          Print only the matched parts of each line
+  --min-matches <N>            Only print files with at least N matching lines
+  --max-matches-per-file <N>   Only print files with at most N matching lines
+  -m, --max-count <N>          Stop reading a file after N matching lines
+  -A <N>, -B <N>, -C <N>       Print N lines of context after, before or around matches
+  --context-merge <N>          Join context groups fewer than N lines apart
+  --context-chars <N>          Print only N characters around each match
+  --summarize-after <N>        Print the first N matches and count the rest
+  --paragraph                  Print whole blank-line-separated blocks that match
+  --in-comments, --in-strings  Only match inside comments or string literals
+  --color <WHEN>               Color the output: auto, always or never
+  --highlight-style <STYLE>    underline, background, bold or reverse
+  --highlight-color <COLOR>    red, green, yellow, blue, magenta or cyan
+  --json                       Print one JSON object per matching line
+  --json-array                 Print all matches as one JSON array
+  --csv [--no-header]          Print matches as CSV rows
+  --emit-to <TARGET>           Stream JSON records to unix:/path or host:port
+  --offset-histogram           Print a histogram of match columns to stderr
+  --keep-going                 Report and skip a file whose search fails unexpectedly
+  --time-budget <DURATION>     Stop searching more files after DURATION, e.g. 2s
+  --checkpoint <FILE>          Record the files searched so far in FILE
+  --continue-from <FILE>       Skip the files recorded in FILE by --checkpoint
+  --stats                      Print match and file counts to stderr
+  --density                    With --stats, also list each file's matches per KiB
+  --resolve-symlinks           Print the real paths of files found through symlinks
+  --text-if-printable <RATIO>  Search binary-looking files with more printable bytes
+  --perm-match <MODE>          With -r, only search files with these octal permissions
+  --owner <USER>               With -r, only search files owned by USER (name or uid)
+  --traversal <ORDER>          Order -r visits files in: dfs (default) or bfs
+  --output-encoding <LABEL>    Re-encode the output, e.g. latin1
+  --count-exit                 Exit with the number of matching lines
+                               (capped at 255; errors still exit 2)
+  -h, --help                   Print this message
+";
+
+/// Why `Config::build` didn't return a config.
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    /// `-h`/`--help` was given, the caller should print `USAGE` instead of searching.
+    Help,
+    /// The arguments don't make a valid search, with a message saying why.
+    Invalid(String),
+}
+
+impl From<&str> for BuildError {
+    fn from(message: &str) -> Self {
+        BuildError::Invalid(message.to_string())
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Help => write!(f, "help requested"),
+            BuildError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+// Flags followed by a value. The value is skipped when looking for flags, so that e.g.
+// `-q -n` searches for "-n" rather than turning on line numbers.
+const VALUE_FLAGS: &[&str] = &[
+    "-q",
+    "--context-chars",
+    "--emit-to",
+    "--summarize-after",
+    "--highlight-style",
+    "--highlight-color",
+    "--output-encoding",
+    "-A",
+    "-B",
+    "-C",
+    "--color",
+    "-m",
+    "--max-count",
+    "--text-if-printable",
+    "--perm-match",
+    "--owner",
+    "--traversal",
+    "--context-merge",
+    "--min-matches",
+    "--max-matches-per-file",
+    "--time-budget",
+    "--recency-weight",
+    "--checkpoint",
+    "--continue-from",
+];
+
+// A --time-budget duration: a number of seconds, or one with an ms, s, m or h suffix
+fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = text.split_at(text.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(text.len()));
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+const UNIX_ONLY: &str = "--perm-match and --owner only work on Unix";
+
+// A user id, given as a number or as a name listed in /etc/passwd
+fn user_id(user: &str) -> Option<u32> {
+    user.parse().ok().or_else(|| {
+        let passwd = fs::read_to_string("/etc/passwd").ok()?;
+        passwd.lines().find_map(|line| {
+            let mut fields = line.split(':');
+            if fields.next()? == user {
+                fields.nth(1)?.parse().ok()
+            } else {
+                None
+            }
+        })
+    })
+}
+
+// Single-letter flags that can be bundled, like -in for -i -n
+const SHORT_SWITCHES: &str = "inrcvwoSFlLh";
+// Single-letter flags with a value, which can end a bundle with the value attached or
+// following, like -iqfoo or -iq foo. -p takes its paths the same way.
+const SHORT_VALUE_FLAGS: &str = "qpmABC";
+
+// Splits bundled short flags into separate arguments, so -in becomes -i -n and -qfoo
+// becomes -q foo. Values of flags are left alone, so `-q -in` still searches for "-in",
+// and bundles with a letter that isn't a flag stay whole to be reported as unknown.
+fn split_short_flags(args: &[String]) -> Vec<String> {
+    let mut split = Vec::with_capacity(args.len());
+    let mut value_next = false;
+
+    for (i, arg) in args.iter().enumerate() {
+        let is_value = std::mem::take(&mut value_next);
+        let bundle = arg
+            .strip_prefix('-')
+            .filter(|rest| i > 0 && !is_value && rest.chars().count() > 1 && !rest.starts_with('-'))
+            .filter(|rest| !rest.contains('='))
+            .filter(|rest| is_bundle(rest));
+        let Some(bundle) = bundle else {
+            value_next = !is_value && i > 0 && VALUE_FLAGS.contains(&arg.as_str());
+            split.push(arg.clone());
+            continue;
+        };
+
+        for (j, letter) in bundle.char_indices() {
+            split.push(format!("-{letter}"));
+            if SHORT_VALUE_FLAGS.contains(letter) {
+                let value = &bundle[j + letter.len_utf8()..];
+                if value.is_empty() {
+                    value_next = letter != 'p';
+                } else {
+                    split.push(value.to_string());
+                }
+                break;
+            }
         }
+    }
+
+    split
+}
+
+// Whether every letter is a switch, up to a flag that takes the rest as its value
+fn is_bundle(letters: &str) -> bool {
+    for letter in letters.chars() {
+        if SHORT_VALUE_FLAGS.contains(letter) {
+            return true;
+        }
+        if !SHORT_SWITCHES.contains(letter) {
+            return false;
+        }
+    }
+    true
+}
 
-        let mut query = String::new();
-        let mut file_path = String::new();
+impl Config {
+    pub fn build(args: &[String]) -> Result<Config, BuildError> {
+        Config::build_with_env(args, env::var_os("IGNORE_CASE").is_some())
+    }
+
+    // build with the IGNORE_CASE variable passed in, so tests don't depend on the process env
+    fn build_with_env(args: &[String], ignore_case_env: bool) -> Result<Config, BuildError> {
+        let args = &split_short_flags(args);
+        let mut query = None;
+        let mut file_paths = Vec::new();
         let mut ignore_case = false;
+        let mut count_exit = false;
+        let mut in_comments = false;
+        let mut in_strings = false;
+        let mut json_array = false;
+        let mut context_chars = None;
+        let mut emit_to = None;
+        let mut summarize_after = None;
+        let mut highlight_style = HighlightStyle::Underline;
+        let mut highlight_color = None;
+        let mut paragraph = false;
+        let mut csv = false;
+        let mut no_header = false;
+        let mut offset_histogram = false;
+        let mut output_encoding = None;
+        let mut line_number = false;
+        let mut regex = false;
+        let mut recursive = false;
+        let mut count = false;
+        let mut invert = false;
+        let mut whole_word = false;
+        let mut before = 0;
+        let mut after = 0;
+        let mut color = ColorChoice::Auto;
+        let mut only_matching = false;
+        let mut max_count = None;
+        let mut json = false;
+        let mut smart_case = false;
+        let mut fixed_strings = false;
+        let mut files_with_matches = false;
+        let mut files_without_match = false;
+        let mut stats = false;
+        let mut resolve_symlinks = false;
+        let mut text_if_printable = None;
+        let mut perm_match = None;
+        let mut owner = None;
+        let mut traversal = Traversal::DepthFirst;
+        let mut context_merge = None;
+        let mut validate_regex = false;
+        let mut min_matches = None;
+        let mut max_matches_per_file = None;
+        let mut density = false;
+        let mut keep_going = false;
+        let mut time_budget = None;
+        let mut rank = false;
+        let mut recency_weight = None;
+        let mut checkpoint = None;
+        let mut continue_from = None;
+        let mut skip_until = 0; // The last argument already used, starting with the program name
 
         for (i, arg) in args.iter().enumerate() {
+            if i <= skip_until {
+                continue;
+            }
+            if VALUE_FLAGS.contains(&arg.as_str()) {
+                skip_until = i + 1;
+            }
+
             if arg == "-q" {
-                query = args[i + 1].clone();
-            } else if arg == "-p" {
-                file_path = args[i + 1].clone();
-            } else if arg == "--ignore-case" {
+                query = Some(args.get(i + 1).ok_or("missing value for -q")?.clone());
+            } else if let Some(value) = arg.strip_prefix("-q=") {
+                query = Some(value.to_string());
+            } else if arg == "-p" || arg.starts_with("-p=") {
+                // Every argument up to the next flag is a path, so shell globs like src/*.rs work.
+                // With -p=PATH the first one is attached to the flag.
+                let attached = arg.strip_prefix("-p=").filter(|path| !path.is_empty());
+                let following: Vec<String> = args[i + 1..]
+                    .iter()
+                    .take_while(|path| !path.starts_with('-') || path.as_str() == "-")
+                    .cloned()
+                    .collect();
+                skip_until = i + following.len();
+                file_paths = attached.map(str::to_string).into_iter().chain(following).collect();
+                if file_paths.is_empty() {
+                    return Err("missing value for -p".into());
+                }
+            } else if arg == "-i" || arg == "--ignore-case" {
                 ignore_case = true;
+            } else if arg == "--count-exit" {
+                count_exit = true;
+            } else if arg == "--in-comments" {
+                in_comments = true;
+            } else if arg == "--in-strings" {
+                in_strings = true;
+            } else if arg == "--json-array" {
+                json_array = true;
+            } else if arg == "--context-chars" {
+                let value = args.get(i + 1).ok_or("missing value for --context-chars")?;
+                context_chars = Some(value.parse().map_err(|_| "--context-chars expects a number")?);
+            } else if arg == "--emit-to" {
+                emit_to = Some(args.get(i + 1).ok_or("missing value for --emit-to")?.clone());
+            } else if arg == "--summarize-after" {
+                let value = args.get(i + 1).ok_or("missing value for --summarize-after")?;
+                summarize_after = Some(value.parse().map_err(|_| "--summarize-after expects a number")?);
+            } else if arg == "--highlight-style" {
+                let value = args.get(i + 1).ok_or("missing value for --highlight-style")?;
+                highlight_style = HighlightStyle::parse(value)?;
+            } else if arg == "--highlight-color" {
+                let value = args.get(i + 1).ok_or("missing value for --highlight-color")?;
+                highlight_color = Some(HighlightColor::parse(value)?);
+            } else if arg == "--paragraph" {
+                paragraph = true;
+            } else if arg == "--csv" {
+                csv = true;
+            } else if arg == "--no-header" {
+                no_header = true;
+            } else if arg == "--offset-histogram" {
+                offset_histogram = true;
+            } else if arg == "--output-encoding" {
+                let label = args.get(i + 1).ok_or("missing value for --output-encoding")?;
+                let encoding = Encoding::for_label(label.as_bytes()).ok_or("unknown output encoding")?;
+                // UTF-16 and "replacement" can only be decoded, encoding_rs writes them as UTF-8
+                if encoding.output_encoding() != encoding {
+                    return Err(BuildError::Invalid(format!("--output-encoding can't write {}", encoding.name())));
+                }
+                output_encoding = Some(encoding).filter(|&encoding| encoding != encoding_rs::UTF_8);
+            } else if arg == "-n" || arg == "--line-number" {
+                line_number = true;
+            } else if arg == "--regex" {
+                regex = true;
+            } else if arg == "-r" || arg == "--recursive" {
+                recursive = true;
+            } else if arg == "-c" || arg == "--count" {
+                count = true;
+            } else if arg == "-v" || arg == "--invert-match" {
+                invert = true;
+            } else if arg == "-w" || arg == "--word-regexp" {
+                whole_word = true;
+            } else if arg == "-A" {
+                let value = args.get(i + 1).ok_or("missing value for -A")?;
+                after = value.parse().map_err(|_| "-A expects a number")?;
+            } else if arg == "-B" {
+                let value = args.get(i + 1).ok_or("missing value for -B")?;
+                before = value.parse().map_err(|_| "-B expects a number")?;
+            } else if arg == "-C" {
+                let value = args.get(i + 1).ok_or("missing value for -C")?;
+                after = value.parse().map_err(|_| "-C expects a number")?;
+                before = after;
+            } else if arg == "--context-merge" {
+                let value = args.get(i + 1).ok_or("missing value for --context-merge")?;
+                context_merge = Some(value.parse().map_err(|_| "--context-merge expects a number")?);
+            } else if arg == "--color" {
+                let value = args.get(i + 1).ok_or("missing value for --color")?;
+                color = ColorChoice::parse(value)?;
+            } else if let Some(value) = arg.strip_prefix("--color=") {
+                color = ColorChoice::parse(value)?;
+            } else if arg == "-o" || arg == "--only-matching" {
+                only_matching = true;
+            } else if arg == "-m" || arg == "--max-count" {
+                let value = args.get(i + 1).ok_or("missing value for -m")?;
+                max_count = Some(value.parse().map_err(|_| "-m expects a number")?);
+            } else if arg == "--json" {
+                json = true;
+            } else if arg == "-S" || arg == "--smart-case" {
+                smart_case = true;
+            } else if arg == "-F" || arg == "--fixed-strings" {
+                fixed_strings = true;
+            } else if arg == "-l" || arg == "--files-with-matches" {
+                files_with_matches = true;
+            } else if arg == "-L" || arg == "--files-without-match" {
+                files_without_match = true;
+            } else if arg == "--stats" {
+                stats = true;
+            } else if arg == "--density" {
+                density = true;
+            } else if arg == "--keep-going" {
+                keep_going = true;
+            } else if arg == "--rank" {
+                rank = true;
+            } else if arg == "--recency-weight" {
+                let value = args.get(i + 1).ok_or("missing value for --recency-weight")?;
+                let weight = value.parse().ok().filter(|weight: &f64| *weight >= 0.0);
+                recency_weight = Some(weight.ok_or("--recency-weight expects a number of at least 0")?);
+            } else if arg == "--checkpoint" {
+                checkpoint = Some(args.get(i + 1).ok_or("missing value for --checkpoint")?.clone());
+            } else if arg == "--continue-from" {
+                continue_from = Some(args.get(i + 1).ok_or("missing value for --continue-from")?.clone());
+            } else if arg == "--time-budget" {
+                let value = args.get(i + 1).ok_or("missing value for --time-budget")?;
+                time_budget = Some(parse_duration(value).ok_or("--time-budget expects a duration like 2s or 500ms")?);
+            } else if arg == "--resolve-symlinks" {
+                resolve_symlinks = true;
+            } else if arg == "--text-if-printable" {
+                let value = args.get(i + 1).ok_or("missing value for --text-if-printable")?;
+                let ratio = value.parse().ok().filter(|ratio| (0.0..=1.0).contains(ratio));
+                text_if_printable = Some(ratio.ok_or("--text-if-printable expects a ratio between 0 and 1")?);
+            } else if arg == "--perm-match" {
+                if !cfg!(unix) {
+                    return Err(UNIX_ONLY.into());
+                }
+                let value = args.get(i + 1).ok_or("missing value for --perm-match")?;
+                let mode = u32::from_str_radix(value, 8).ok().filter(|mode| *mode <= 0o7777);
+                perm_match = Some(mode.ok_or("--perm-match expects octal permission bits like 644")?);
+            } else if arg == "--owner" {
+                if !cfg!(unix) {
+                    return Err(UNIX_ONLY.into());
+                }
+                let value = args.get(i + 1).ok_or("missing value for --owner")?;
+                owner = Some(user_id(value).ok_or_else(|| BuildError::Invalid(format!("unknown user for --owner: {value}")))?);
+            } else if arg == "--min-matches" {
+                let value = args.get(i + 1).ok_or("missing value for --min-matches")?;
+                min_matches = Some(value.parse().map_err(|_| "--min-matches expects a number")?);
+            } else if arg == "--max-matches-per-file" {
+                let value = args.get(i + 1).ok_or("missing value for --max-matches-per-file")?;
+                max_matches_per_file = Some(value.parse().map_err(|_| "--max-matches-per-file expects a number")?);
+            } else if arg == "--validate-regex" {
+                validate_regex = true;
+            } else if arg == "--traversal" {
+                let value = args.get(i + 1).ok_or("missing value for --traversal")?;
+                traversal = Traversal::parse(value)?;
+            } else if arg == "-h" || arg == "--help" {
+                return Err(BuildError::Help);
+            } else if arg.starts_with('-') {
+                return Err(BuildError::Invalid(format!("unknown flag: {arg}")));
+            } else {
+                // Not a flag or a flag's value, like a file given without -p
+                return Err(BuildError::Invalid(format!("unexpected argument: {arg}")));
+            }
+        }
+
+        // -p is optional, without it the input is read from stdin
+        let query = query.ok_or("missing query, pass one with -q")?;
+        // Queries are literal unless --regex is given, -F just says so explicitly
+        if regex && fixed_strings {
+            return Err("--regex and -F/--fixed-strings conflict, pass only one of them".into());
+        }
+        if validate_regex && fixed_strings {
+            return Err("--validate-regex and -F/--fixed-strings conflict, pass only one of them".into());
+        }
+        // Each of these decides what gets printed for the matches, so only one may be given
+        let structured = [
+            (json, "--json"),
+            (json_array, "--json-array"),
+            (csv, "--csv"),
+            (emit_to.is_some(), "--emit-to"),
+        ];
+        let output_modes = [
+            (paragraph, "--paragraph"),
+            (count, "-c/--count"),
+            (rank, "--rank"),
+            (files_with_matches, "-l/--files-with-matches"),
+            (files_without_match, "-L/--files-without-match"),
+        ];
+        // The structured formats have nowhere to put the "... and K more" note
+        if let (Some(_), Some((_, flag))) = (summarize_after, structured.iter().find(|(on, _)| *on)) {
+            return Err(BuildError::Invalid(format!("--summarize-after only works with text output, not {flag}")));
+        }
+        let mut given = output_modes.iter().chain(&structured).filter(|(on, _)| *on).map(|(_, flag)| flag);
+        if let (Some(first), Some(second)) = (given.next(), given.next()) {
+            return Err(BuildError::Invalid(format!("{first} and {second} conflict, pass only one of them")));
+        }
+        // Paragraphs are matched and printed whole, so the options picking lines or parts of lines don't apply
+        let line_options = [
+            (max_count.is_some(), "-m/--max-count"),
+            (summarize_after.is_some(), "--summarize-after"),
+            (in_comments, "--in-comments"),
+            (in_strings, "--in-strings"),
+            (only_matching, "-o/--only-matching"),
+            (context_chars.is_some(), "--context-chars"),
+            (before > 0 || after > 0, "-A/-B/-C"),
+        ];
+        if let (true, Some((_, flag))) = (paragraph, line_options.iter().find(|(on, _)| *on)) {
+            return Err(BuildError::Invalid(format!("--paragraph and {flag} conflict, pass only one of them")));
+        }
+        // Context lines are printed whole, which doesn't go with printing only parts of the matching ones
+        let line_parts = [(only_matching, "-o/--only-matching"), (context_chars.is_some(), "--context-chars")];
+        if let (true, Some((_, flag))) = (before > 0 || after > 0, line_parts.iter().find(|(on, _)| *on)) {
+            return Err(BuildError::Invalid(format!("-A/-B/-C and {flag} conflict, pass only one of them")));
+        }
+        // These filter the files -r finds, files named with -p are searched as given
+        if (perm_match.is_some() || owner.is_some()) && !recursive {
+            return Err("--perm-match and --owner need -r/--recursive".into());
+        }
+        // These need every matching line of a file counted, and -L only lists files without any
+        let count_filters = [(min_matches.is_some(), "--min-matches"), (max_matches_per_file.is_some(), "--max-matches-per-file")];
+        let uncounted = [(max_count.is_some(), "-m/--max-count"), (files_without_match, "-L/--files-without-match")];
+        if let (Some((_, first)), Some((_, second))) =
+            (count_filters.iter().find(|(on, _)| *on), uncounted.iter().find(|(on, _)| *on))
+        {
+            return Err(BuildError::Invalid(format!("{first} and {second} conflict, pass only one of them")));
+        }
+        if let (Some(min), Some(max)) = (min_matches, max_matches_per_file) {
+            if min > max {
+                return Err("--min-matches can't be more than --max-matches-per-file".into());
+            }
+        }
+        if recency_weight.is_some() && !rank {
+            return Err("--recency-weight needs --rank".into());
+        }
+        if density && !stats {
+            return Err("--density needs --stats".into());
+        }
+        // Listing files stops at their first matches, which says nothing about how dense they are
+        let listed = [(files_with_matches, "-l/--files-with-matches"), (files_without_match, "-L/--files-without-match")];
+        if let (true, Some((_, flag))) = (density, listed.iter().find(|(on, _)| *on)) {
+            return Err(BuildError::Invalid(format!("--density and {flag} conflict, pass only one of them")));
+        }
+        if context_merge.is_some() && before == 0 && after == 0 {
+            return Err("--context-merge needs -A/-B/-C".into());
+        }
+        // Like the Rust Book's minigrep, IGNORE_CASE set to anything ignores case by default
+        if ignore_case_env {
+            ignore_case = true;
+        }
+        // Smart case only ever turns case-insensitivity on, so --ignore-case still wins
+        if smart_case && !query.chars().any(char::is_uppercase) {
+            ignore_case = true;
+        }
+        Ok(Config {
+            query,
+            file_paths,
+            ignore_case,
+            count_exit,
+            in_comments,
+            in_strings,
+            json_array,
+            context_chars,
+            emit_to,
+            summarize_after,
+            highlight_style,
+            highlight_color,
+            paragraph,
+            csv,
+            no_header,
+            offset_histogram,
+            output_encoding,
+            line_number,
+            regex: regex || validate_regex,
+            recursive,
+            // Ranking needs the same per-file counts as -c, which Sink::Ranked then sorts
+            count: count || rank,
+            invert,
+            whole_word,
+            before,
+            after,
+            // Groups that touch or overlap are always printed as one
+            context_merge: context_merge.unwrap_or(1).max(1),
+            color: color.resolve(),
+            only_matching,
+            max_count,
+            json,
+            files_with_matches,
+            files_without_match,
+            stats,
+            resolve_symlinks,
+            text_if_printable,
+            perm_match,
+            owner,
+            traversal,
+            validate_regex,
+            min_matches: min_matches.unwrap_or(0),
+            max_matches_per_file,
+            density,
+            keep_going,
+            time_budget,
+            rank,
+            recency_weight: recency_weight.unwrap_or(1.0),
+            checkpoint,
+            continue_from,
+        })
+
+
+    }
+
+    // Filenames are only worth printing when more than one file can match
+    fn shows_paths(&self) -> bool {
+        self.recursive || self.file_paths.len() > 1
+    }
+
+    // The path printed for a file. With --resolve-symlinks it is where the file really is,
+    // or the path as given, with a warning, when that can't be worked out.
+    fn output_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if !self.resolve_symlinks {
+            return Cow::Borrowed(path);
+        }
+        match fs::canonicalize(path) {
+            Ok(real) => Cow::Owned(real.to_string_lossy().into_owned()),
+            Err(err) => {
+                eprintln!("warning: could not resolve {path} ({err}), printing it as given");
+                Cow::Borrowed(path)
+            }
+        }
+    }
+
+    // With --min-matches or --max-matches-per-file, a file's output waits until its matches are counted
+    fn filters_match_counts(&self) -> bool {
+        self.min_matches > 0 || self.max_matches_per_file.is_some()
+    }
+
+    // Whether every file is searched into a buffer first, like the files -r finds. That is
+    // what --min-matches and --max-matches-per-file count, what --keep-going drops when a
+    // search fails and what --rank takes each file's count and age from. It is also where
+    // --checkpoint and --continue-from keep track of the files.
+    fn buffers_files(&self) -> bool {
+        self.filters_match_counts()
+            || self.keep_going
+            || self.rank
+            || self.checkpoint.is_some()
+            || self.continue_from.is_some()
+    }
+
+    // Whether a file with this many matching lines is printed
+    fn keeps_match_count(&self, matches: usize) -> bool {
+        matches >= self.min_matches && self.max_matches_per_file.is_none_or(|max| matches <= max)
+    }
+
+    // -l and -L print file names instead of lines
+    fn lists_files(&self) -> bool {
+        self.files_with_matches || self.files_without_match
+    }
+
+    // How many matches to look for in each file. -m counts per file, and listing a file
+    // only needs enough matches to pass --min-matches and fail --max-matches-per-file.
+    fn match_limit(&self) -> usize {
+        if self.lists_files() {
+            self.max_matches_per_file.map_or(self.min_matches, |max| max + 1).max(1)
+        } else {
+            self.max_count.unwrap_or(usize::MAX)
+        }
+    }
+
+    // The escape codes that start a highlighted match. Without color it is printed plainly.
+    fn highlight(&self) -> String {
+        if self.color {
+            self.highlight_style.codes(self.highlight_color)
+        } else {
+            String::new()
+        }
+    }
+
+    // The escape code when color is on, nothing otherwise
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.color {
+            code
+        } else {
+            ""
+        }
+    }
+
+    /// Whether the caller should exit with the match count (capped at 255).
+    /// An exit code of 0 still means nothing matched.
+    pub fn count_exit(&self) -> bool {
+        self.count_exit
+    }
+
+    /// Whether the caller should only check that the query compiles, with `check_pattern`,
+    /// instead of searching.
+    pub fn validate_regex(&self) -> bool {
+        self.validate_regex
+    }
+
+    /// The query given with `-q`.
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// The paths given with `-p`, empty when standard input is searched.
+    pub fn file_paths(&self) -> &[String] {
+        &self.file_paths
+    }
+
+    /// Whether matching ignores case, from `-i`/`--ignore-case` or `--smart-case` with
+    /// an all-lowercase query.
+    pub fn ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+}
+
+// Byte ranges of every non-overlapping occurrence of query in line, left to right
+fn match_ranges(query: &str, line: &str, ignore_case: bool) -> Vec<(usize, usize)> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    if !ignore_case {
+        return line.match_indices(query).map(|(start, m)| (start, start + m.len())).collect();
+    }
+
+    // Compare lowercased chars so the ranges still point into the original line
+    let query: Vec<char> = query.to_lowercase().chars().collect();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let mut lowered = Vec::new();
+        let mut end = start;
+        for (offset, c) in line[start..].char_indices() {
+            if lowered.len() >= query.len() {
+                break;
             }
+            lowered.extend(c.to_lowercase());
+            end = start + offset + c.len_utf8();
+        }
+        if lowered == query {
+            ranges.push((start, end));
+            start = end;
+        } else {
+            start += line[start..].chars().next().map(char::len_utf8).unwrap_or(1);
+        }
+    }
+    ranges
+}
+
+// What lines are matched against: the literal query, or the pattern compiled from it with --regex
+enum Pattern<'a> {
+    Literal { query: &'a str, ignore_case: bool, whole_word: bool },
+    Regex(Regex),
+}
+
+impl Pattern<'_> {
+    fn build(config: &Config) -> Result<Pattern<'_>, Box<dyn Error>> {
+        if !config.regex {
+            return Ok(Pattern::Literal {
+                query: &config.query,
+                ignore_case: config.ignore_case,
+                whole_word: config.whole_word,
+            });
         }
-        Ok(Config{ query, file_path, ignore_case})
+        let source = if config.whole_word { format!(r"\b(?:{})\b", config.query) } else { config.query.clone() };
+        let re = RegexBuilder::new(&source)
+            .case_insensitive(config.ignore_case)
+            .build()
+            .map_err(|err| format!("invalid regex pattern {:?}: {}", config.query, err))?;
+        Ok(Pattern::Regex(re))
+    }
 
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Pattern::Literal { whole_word: true, .. } => !self.ranges(text).is_empty(),
+            Pattern::Literal { query, ignore_case: false, .. } => text.contains(query),
+            Pattern::Literal { query, ignore_case: true, .. } => text.to_lowercase().contains(&query.to_lowercase()),
+            Pattern::Regex(re) => re.is_match(text),
+        }
+    }
 
+    fn ranges(&self, line: &str) -> Vec<(usize, usize)> {
+        match self {
+            Pattern::Literal { query, ignore_case, whole_word } => {
+                let ranges = match_ranges(query, line, *ignore_case);
+                if *whole_word {
+                    ranges.into_iter().filter(|&(start, end)| is_whole_word(line, start, end)).collect()
+                } else {
+                    ranges
+                }
+            }
+            Pattern::Regex(re) => re
+                .find_iter(line)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        }
     }
 }
 
-fn find_word(query: &str, line: &str) -> usize {
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Whether text[start..end] has a non-word character (or the edge of the line) on both sides
+fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back().is_none_or(|c| !is_word_char(c));
+    let after = text[end..].chars().next().is_none_or(|c| !is_word_char(c));
+    before && after
+}
+
+// Index of the whitespace-separated word that byte offset `pos` falls in (or follows)
+fn word_index_at(line: &str, pos: usize) -> usize {
+    let mut index = 0;
     for (i, word) in line.split_whitespace().enumerate() {
-        if word.contains(&query) {
-            return  i as usize
+        let start = word.as_ptr() as usize - line.as_ptr() as usize;
+        if start > pos {
+            break;
         }
+        index = i;
     }
-    0
+    index
 }
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.file_path)?;
+// Up to `chars` characters on each side of line[start..end], with ellipses where the line was cut
+fn context_window(line: &str, start: usize, end: usize, chars: usize) -> (String, &str, String) {
+    let before_start = line[..start]
+        .char_indices()
+        .rev()
+        .take(chars)
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(start);
+    let after_end = line[end..]
+        .char_indices()
+        .nth(chars)
+        .map(|(i, _)| end + i)
+        .unwrap_or(line.len());
 
-    let results = if config.ignore_case {
-        ignore_case_search(&config.query, &contents)
+    let before = if before_start > 0 {
+        format!("…{}", &line[before_start..start])
     } else {
-        normal_search(&config.query, &contents)
+        line[..start].to_string()
     };
+    let after = if after_end < line.len() {
+        format!("{}…", &line[end..after_end])
+    } else {
+        line[end..].to_string()
+    };
+    (before, &line[start..end], after)
+}
+
+// Opens the --emit-to target: `unix:/path/to.sock` or a TCP address like `127.0.0.1:9000`
+fn connect_emitter(target: &str) -> io::Result<Box<dyn Write>> {
+    if let Some(path) = target.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?));
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unix sockets are not supported on this platform: {path}"),
+        ));
+    }
+    let address = target.strip_prefix("tcp:").unwrap_or(target);
+    Ok(Box::new(TcpStream::connect(address)?))
+}
+
+// Writes a matching line with the word containing its first match highlighted
+fn write_highlighted(
+    out: &mut dyn Write,
+    config: &Config,
+    line: &str,
+    ranges: &[(usize, usize)],
+    highlight: &str,
+) -> io::Result<()> {
+    let position = ranges.first().map_or(0, |&(start, _)| word_index_at(line, start));
+    for (i, word) in line.split_whitespace().enumerate() {
+        if i == position {
+            write!(out, "{}{}{} ", highlight, word, config.paint(RESET))?;
+        } else {
+            write!(out, "{} ", word)?;
+        }
+    }
+    writeln!(out)
+}
+
+// Prints every matching paragraph whole, its first line prefixed with the line it starts on
+fn print_paragraphs(
+    out: &mut dyn Write,
+    config: &Config,
+    pattern: &Pattern,
+    path: Option<&str>,
+    contents: &str,
+    highlight: &str,
+) -> io::Result<usize> {
+    let results = paragraph_search(pattern, contents, config.invert);
+
+    for (n, (start, block)) in results.iter().enumerate() {
+        if n > 0 {
+            writeln!(out)?;
+        }
+        if let Some(path) = path {
+            write!(out, "{}{}{}:", config.paint(MAGENTA), path, config.paint(RESET))?;
+        }
+        write!(out, "{}:", start)?;
+        for line in block {
+            if !pattern.is_match(line) {
+                writeln!(out, "{}", line)?;
+            } else {
+                write_highlighted(out, config, line, &pattern.ranges(line), highlight)?;
+            }
+        }
+    }
+
+    Ok(results.len())
+}
+
+const HISTOGRAM_BUCKET: usize = 10; // Columns per histogram bucket
+const HISTOGRAM_WIDTH: usize = 40; // Length of the longest bar
+
+// Counts how many first-match columns fall in each bucket, from column 0 up to the largest one
+fn offset_buckets(offsets: &[usize]) -> Vec<usize> {
+    let Some(&max) = offsets.iter().max() else { return Vec::new() };
+    let mut buckets = vec![0; max / HISTOGRAM_BUCKET + 1];
+    for &offset in offsets {
+        buckets[offset / HISTOGRAM_BUCKET] += 1;
+    }
+    buckets
+}
+
+// Prints a text histogram of where in the line the first match sits to stderr
+fn print_offset_histogram(offsets: &[usize]) {
+    let buckets = offset_buckets(offsets);
+    let largest = buckets.iter().copied().max().unwrap_or(0).max(1);
+
+    eprintln!("first match offset histogram ({} lines):", offsets.len());
+    for (i, &count) in buckets.iter().enumerate() {
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(largest));
+        let start = i * HISTOGRAM_BUCKET;
+        eprintln!("{:>5}-{:<5} | {} {}", start, start + HISTOGRAM_BUCKET - 1, bar, count);
+    }
+}
+
+// Writes the `path:` prefix when several files are searched and the `N:` prefix for -n
+// Matching lines use `:` as the separator and context lines use `-`, like grep
+fn write_prefix(
+    out: &mut dyn Write,
+    config: &Config,
+    path: Option<&str>,
+    line_number: usize,
+    separator: char,
+) -> io::Result<()> {
+    if let Some(path) = path {
+        write!(out, "{}{}{}{}", config.paint(MAGENTA), path, config.paint(RESET), separator)?;
+    }
+    if config.line_number {
+        write!(out, "{}{}{}{}", config.paint(CYAN), line_number, config.paint(RESET), separator)?;
+    }
+    Ok(())
+}
+
+fn write_match_line(
+    out: &mut dyn Write,
+    config: &Config,
+    path: Option<&str>,
+    found: &LineMatch,
+    highlight: &str,
+) -> io::Result<()> {
+    write_prefix(out, config, path, found.line_number, ':')?;
+    if config.invert {
+        // Nothing in an inverted match to highlight
+        writeln!(out, "{}", found.line)
+    } else {
+        write_highlighted(out, config, found.line, &found.ranges, highlight)
+    }
+}
+
+// Prints the matches with -B lines before and -A lines after each. Windows that overlap or
+// touch are merged into one group, and separate groups are divided by `--`. `grouped` says
+// whether an earlier file printed a group already, and is set once this one does.
+fn write_with_context(
+    out: &mut dyn Write,
+    grouped: &mut bool,
+    config: &Config,
+    path: Option<&str>,
+    contents: &str,
+    results: &[LineMatch],
+    highlight: &str,
+) -> io::Result<()> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let matched: HashMap<usize, &LineMatch> = results.iter().map(|found| (found.line_number, found)).collect();
+    let mut last_printed: Option<usize> = None;
+
+    for &LineMatch { line_number, .. } in results {
+        let first = line_number.saturating_sub(config.before).max(1);
+        let last = (line_number + config.after).min(lines.len());
+        let start = match last_printed {
+            // Fewer than context_merge lines apart, the lines in between are printed as context
+            Some(printed) if printed + config.context_merge >= first => printed + 1,
+            // Separate groups, which includes this file's first one and the previous file's last
+            _ if *grouped => {
+                writeln!(out, "--")?;
+                first
+            }
+            _ => first,
+        };
+        *grouped = true;
 
-    for line in results {
-        let position = find_word(&config.query, &line);
-        for (i, word) in line.split_whitespace().enumerate() {
-            if i == position {
-                print!("{}{}{}{} ", RED, UNDERLINE, word, RESET);
+        for n in start..=last {
+            if let Some(found) = matched.get(&n) {
+                write_match_line(out, config, path, found, highlight)?;
             } else {
-                print!("{} ", word);
+                write_prefix(out, config, path, n, '-')?;
+                writeln!(out, "{}", lines[n - 1])?;
             }
         }
-        println!();
+        last_printed = Some(last_printed.map_or(last, |printed| printed.max(last)));
     }
+
     Ok(())
 }
+
+// Where matches are written. JSON-array output carries its buffered records from one file
+// to the next, so it lives here rather than in run. Text output likewise carries whether a
+// -A/-B/-C group was printed, so that the next file's first group is divided from it by `--`.
+// --rank carries its recency weight and the score of every file with matches, printed
+// highest first once all files are searched.
+enum Sink<'a> {
+    Text(Box<dyn Write + 'a>, bool),
+    Csv(Box<dyn Write + 'a>),
+    JsonArray(Box<dyn Write + 'a>, Vec<MatchRecord>),
+    Json(Box<dyn Write + 'a>),
+    Emit(Box<dyn Write + 'a>),
+    Ranked(Box<dyn Write + 'a>, f64, Vec<(String, f64)>),
+}
+
+impl<'a> Sink<'a> {
+    fn new(config: &Config, out: Box<dyn Write + 'a>) -> Result<Sink<'a>, Box<dyn Error>> {
+        // Before -c, which --rank also sets
+        if config.rank {
+            return Ok(Sink::Ranked(out, config.recency_weight, Vec::new()));
+        }
+        // These print text of their own, and Config::build keeps them apart from the structured formats
+        if config.paragraph || config.count || config.lists_files() {
+            return Ok(Sink::Text(out, false));
+        }
+        if config.json_array {
+            return Ok(Sink::JsonArray(out, Vec::new()));
+        }
+        if config.json {
+            return Ok(Sink::Json(out));
+        }
+        if config.csv {
+            // path,line,column,text rows; the csv crate takes care of quoting commas and quotes
+            let mut out = out;
+            if !config.no_header {
+                let mut writer = csv::Writer::from_writer(&mut out);
+                writer.write_record(["path", "line", "column", "text"])?;
+                writer.flush()?;
+            }
+            return Ok(Sink::Csv(out));
+        }
+        if let Some(target) = &config.emit_to {
+            // JSON is always UTF-8, so --output-encoding doesn't apply to the stream
+            let stream = connect_emitter(target).unwrap_or_else(|err| {
+                eprintln!("warning: could not connect to {target} ({err}), writing matches to stdout");
+                out
+            });
+            return Ok(Sink::Emit(stream));
+        }
+        Ok(Sink::Text(out, false))
+    }
+
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Sink::Text(mut out, _) | Sink::Csv(mut out) | Sink::Json(mut out) | Sink::Emit(mut out) => out.flush()?,
+            Sink::JsonArray(mut out, records) => {
+                // One JSON array holding every match, without any color escapes
+                writeln!(out, "{}", serde_json::to_string(&records)?)?;
+                out.flush()?;
+            }
+            Sink::Ranked(mut out, _, mut scores) => {
+                scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+                for (path, score) in scores {
+                    writeln!(out, "{score:.2}  {path}")?;
+                }
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // An empty sink of the same kind writing into buf, so a worker thread can search a
+    // file on its own and the output can be written out in order afterwards
+    fn buffered(kind: SinkKind, buf: &mut Vec<u8>) -> Sink<'_> {
+        match kind {
+            SinkKind::Text => Sink::Text(Box::new(buf), false),
+            SinkKind::Csv => Sink::Csv(Box::new(buf)),
+            SinkKind::JsonArray => Sink::JsonArray(Box::new(buf), Vec::new()),
+            SinkKind::Json => Sink::Json(Box::new(buf)),
+            SinkKind::Emit => Sink::Emit(Box::new(buf)),
+        }
+    }
+
+    fn kind(&self) -> SinkKind {
+        match self {
+            // Ranked files are searched like -c, into text buffers that absorb only counts
+            Sink::Text(..) | Sink::Ranked(..) => SinkKind::Text,
+            Sink::Csv(_) => SinkKind::Csv,
+            Sink::JsonArray(..) => SinkKind::JsonArray,
+            Sink::Json(_) => SinkKind::Json,
+            Sink::Emit(_) => SinkKind::Emit,
+        }
+    }
+
+    // Whether a text sink printed a -A/-B/-C group
+    fn grouped(&self) -> bool {
+        matches!(self, Sink::Text(_, true))
+    }
+
+    // The records a JSON-array sink buffered, empty for every other kind
+    fn into_records(self) -> Vec<MatchRecord> {
+        match self {
+            Sink::JsonArray(_, records) => records,
+            _ => Vec::new(),
+        }
+    }
+
+    // Takes over what a buffered sink collected for one file
+    fn absorb(&mut self, output: &FileOutput) -> io::Result<()> {
+        match self {
+            Sink::JsonArray(_, all) => all.extend(output.records.iter().cloned()),
+            Sink::Text(out, grouped) => {
+                if *grouped && output.grouped {
+                    writeln!(out, "--")?;
+                }
+                *grouped |= output.grouped;
+                out.write_all(&output.bytes)?;
+            }
+            Sink::Csv(out) | Sink::Json(out) | Sink::Emit(out) => out.write_all(&output.bytes)?,
+            // A day old file scores as if it had 1 / (1 + weight) as many matches
+            Sink::Ranked(_, weight, scores) if output.matches > 0 => {
+                let age = output.modified.and_then(|modified| SystemTime::now().duration_since(modified).ok());
+                let days = age.map_or(0.0, |age| age.as_secs_f64() / 86_400.0);
+                scores.push((output.path.clone(), output.matches as f64 / (1.0 + *weight * days)));
+            }
+            Sink::Ranked(..) => {}
+        }
+        Ok(())
+    }
+}
+
+// Which Sink variant is in use, without the writer, so it can be sent to worker threads
+#[derive(Clone, Copy)]
+enum SinkKind {
+    Text,
+    Csv,
+    JsonArray,
+    Json,
+    Emit,
+}
+
+// Everything one file produced on a worker thread, written out later by the main thread
+#[derive(Default)]
+struct FileOutput {
+    bytes: Vec<u8>,
+    records: Vec<MatchRecord>,
+    matches: usize,
+    offsets: Vec<usize>,
+    error: Option<String>, // The file couldn't be read, reported on stderr in order
+    skipped: bool,         // Binary, so never searched
+    grouped: bool,         // Printed a -A/-B/-C group, see Sink::Text
+    path: String,          // As printed, for --rank
+    modified: Option<SystemTime>, // For --rank, None for standard input
+}
+
+// Counters for the --stats summary
+#[derive(Default)]
+struct Stats {
+    matches: usize,
+    files_matched: usize,
+    files_searched: usize,
+    errors: usize, // Files and directories that couldn't be read and were skipped
+    lists_files: bool, // -l/-L stop reading a file at its first match, so only files are counted
+    densities: Option<Vec<(String, f64)>>, // Matches per KiB of each matching file, with --density
+}
+
+impl Stats {
+    fn add_file(&mut self, matches: usize) {
+        self.matches += matches;
+        self.files_searched += 1;
+        if matches > 0 {
+            self.files_matched += 1;
+        }
+    }
+
+    // Notes down how dense the file's matches are, when --density asks for it
+    fn add_density(&mut self, path: &Path, matches: usize) {
+        let Some(densities) = self.densities.as_mut().filter(|_| matches > 0) else { return };
+        if let Ok(metadata) = fs::metadata(path) {
+            densities.push((path.display().to_string(), matches as f64 * 1024.0 / metadata.len().max(1) as f64));
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.lists_files {
+            write!(f, "{} files matched (searched {})", self.files_matched, self.files_searched)?;
+        } else {
+            write!(f, "{} matches in {} files (searched {})", self.matches, self.files_matched, self.files_searched)?;
+        }
+        // The densest files first, one per line below the summary
+        if let Some(densities) = &self.densities {
+            let mut densest: Vec<_> = densities.iter().collect();
+            densest.sort_by(|a, b| b.1.total_cmp(&a.1));
+            for (path, density) in densest {
+                write!(f, "\n{density:.2} matches/KiB  {path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn search_file(config: &Config, pattern: &Pattern, kind: SinkKind, path: &Path) -> Result<FileOutput, String> {
+    #[cfg(test)]
+    if path.ends_with("minigrep-panics.txt") {
+        panic!("search of {} panicked on purpose", path.display());
+    }
+    if is_binary(path, config.text_if_printable) {
+        return Ok(FileOutput { skipped: true, ..Default::default() });
+    }
+
+    let shown = path.to_string_lossy();
+    let searched = fs::File::open(path).map_err(Into::into).and_then(|file| -> Result<FileOutput, Box<dyn Error>> {
+        let modified = file.metadata().and_then(|metadata| metadata.modified()).ok();
+        let output = search_to_buffer(config, pattern, kind, &config.output_path(&shown), BufReader::new(file))?;
+        Ok(FileOutput { modified, ..output })
+    });
+    // An unreadable file shouldn't end the whole search. The buffer only ever fails on
+    // reading, so whatever the file printed before the error is dropped with it.
+    Ok(searched.unwrap_or_else(|err| FileOutput { error: Some(format!("{}: {}", path.display(), err)), ..Default::default() }))
+}
+
+// search_file, except that with --keep-going a panic is caught and turned into the file's
+// error. Everything a search writes to is its own, so nothing is left half updated.
+fn search_file_guarded(config: &Config, pattern: &Pattern, kind: SinkKind, path: &Path) -> Result<FileOutput, String> {
+    if !config.keep_going {
+        return search_file(config, pattern, kind, path);
+    }
+    panic::catch_unwind(AssertUnwindSafe(|| search_file(config, pattern, kind, path))).unwrap_or_else(|panic| {
+        let reason = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str));
+        let error = format!("{}: search failed, skipping it: {}", path.display(), reason.unwrap_or("unknown panic"));
+        Ok(FileOutput { error: Some(error), ..Default::default() })
+    })
+}
+
+// Searches reader into a buffer, for output that is written later or not at all
+fn search_to_buffer(
+    config: &Config,
+    pattern: &Pattern,
+    kind: SinkKind,
+    path: &str,
+    reader: impl BufRead,
+) -> Result<FileOutput, Box<dyn Error>> {
+    let mut output = FileOutput::default();
+    let mut bytes = Vec::new();
+    let mut sink = Sink::buffered(kind, &mut bytes);
+    output.matches = search_reader(&mut sink, config, pattern, path, reader, &mut output.offsets)?;
+    output.grouped = sink.grouped();
+    output.records = sink.into_records();
+    output.bytes = bytes;
+    output.path = path.to_string();
+    Ok(output)
+}
+
+// Writes out what a file produced, unless it has too few or too many matches for
+// --min-matches and --max-matches-per-file. Such a file still counts as searched.
+// Standard input has no path, and no size for --density.
+fn write_file_output(
+    sink: &mut Sink,
+    config: &Config,
+    path: Option<&Path>,
+    output: FileOutput,
+    offsets: &mut Vec<usize>,
+    stats: &mut Stats,
+) -> io::Result<()> {
+    if !config.keeps_match_count(output.matches) {
+        stats.add_file(0);
+        return Ok(());
+    }
+    sink.absorb(&output)?;
+    offsets.extend(output.offsets);
+    stats.add_file(output.matches);
+    if let Some(path) = path {
+        stats.add_density(path, output.matches);
+    }
+    Ok(())
+}
+
+const CHECKPOINT_EVERY: usize = 100; // Files searched between two writes of --checkpoint
+
+// How far a run has got: the deadline of --time-budget, and the files searched already for
+// --continue-from and --checkpoint
+struct Progress {
+    deadline: Option<Instant>,
+    done: HashSet<PathBuf>, // Read from --continue-from, plus the files searched since
+    checkpoint: Option<PathBuf>,
+    unsaved: usize, // Files searched since the checkpoint was last written
+}
+
+impl Progress {
+    fn new(config: &Config) -> Result<Progress, Box<dyn Error>> {
+        let mut done = HashSet::new();
+        if let Some(path) = &config.continue_from {
+            match fs::read_to_string(path) {
+                Ok(contents) => done.extend(contents.lines().map(PathBuf::from)),
+                // Not written yet, like on the first run of a command checkpointing to the same file
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(format!("could not read checkpoint {}: {}", path, err).into()),
+            }
+        }
+        Ok(Progress {
+            deadline: config.time_budget.map(|budget| Instant::now() + budget),
+            done,
+            checkpoint: config.checkpoint.as_ref().map(PathBuf::from),
+            unsaved: 0,
+        })
+    }
+
+    fn out_of_time(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Notes down a searched file, writing the checkpoint every CHECKPOINT_EVERY files
+    fn finish_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if self.checkpoint.is_none() {
+            return Ok(());
+        }
+        self.done.insert(path.to_path_buf());
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_EVERY {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Writes the searched files to the checkpoint, one path per line. They go to a temporary
+    // file first that then replaces the checkpoint, so a run stopped halfway through writing
+    // it leaves the previous checkpoint whole.
+    fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(checkpoint) = &self.checkpoint else { return Ok(()) };
+        let mut paths: Vec<_> = self.done.iter().map(|path| path.to_string_lossy()).collect();
+        paths.sort();
+        let contents: String = paths.iter().map(|path| format!("{path}\n")).collect();
+        let temporary = PathBuf::from(format!("{}.tmp", checkpoint.display()));
+        let written = fs::File::create(&temporary)
+            .and_then(|mut file| file.write_all(contents.as_bytes()).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temporary, checkpoint));
+        written.map_err(|err| format!("could not write checkpoint {}: {}", checkpoint.display(), err))?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+// Searches the files on a few worker threads. Every file gets its own channel and the
+// main thread reads them in order, so the output is the same as searching one by one.
+// Files a checkpoint lists are left out. Returns whether it stopped early because the
+// deadline of --time-budget passed.
+fn search_files(
+    sink: &mut Sink,
+    config: &Config,
+    pattern: &Pattern,
+    files: &[PathBuf],
+    offsets: &mut Vec<usize>,
+    stats: &mut Stats,
+    progress: &mut Progress,
+) -> Result<bool, Box<dyn Error>> {
+    let files: Vec<&PathBuf> = files.iter().filter(|file| !progress.done.contains(*file)).collect();
+    let kind = sink.kind();
+    let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(files.len());
+    let next = AtomicUsize::new(0);
+    // Set when the deadline passes, so the workers don't start on any more files
+    let stop = AtomicBool::new(false);
+    let (senders, receivers): (Vec<_>, Vec<_>) = files.iter().map(|_| mpsc::channel()).unzip();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(i) else { break };
+                // The receiver is gone once the main thread stopped on an error
+                if senders[i].send(search_file_guarded(config, pattern, kind, path)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        for (receiver, &path) in receivers.into_iter().zip(&files) {
+            let received = match progress.deadline {
+                Some(deadline) => match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        stop.store(true, Ordering::Relaxed);
+                        return Ok(true);
+                    }
+                    received => received.ok(),
+                },
+                None => receiver.recv().ok(),
+            };
+            let output = received.ok_or("search thread stopped")??;
+            // Left out of the checkpoint, so that a rerun tries again
+            if let Some(error) = output.error {
+                eprintln!("{}", error);
+                stats.errors += 1;
+                continue;
+            }
+            if !output.skipped {
+                write_file_output(sink, config, Some(path), output, offsets, stats)?;
+            }
+            progress.finish_file(path)?;
+        }
+        Ok(false)
+    })
+}
+
+const BINARY_SNIFF_LEN: usize = 8192; // Bytes checked for a NUL when deciding a file is binary
+
+/// Whether `bytes`, the start of a file, look binary: a NUL byte within the first few KB.
+/// UTF-8 text never contains one, however much non-ASCII it has.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+// Whether the file at path is skipped as binary. With --text-if-printable a file that looks
+// binary is still searched when more than that share of its first bytes is printable, like
+// text with a stray NUL. Unreadable files count as text so that reading them reports the error.
+fn is_binary(path: &Path, text_if_printable: Option<f64>) -> bool {
+    let mut head = Vec::new();
+    let Ok(file) = fs::File::open(path) else { return false };
+    if file.take(BINARY_SNIFF_LEN as u64).read_to_end(&mut head).is_err() || !looks_binary(&head) {
+        return false;
+    }
+    text_if_printable.is_none_or(|threshold| printable_ratio(&head) <= threshold)
+}
+
+// The share of bytes that could be text: visible ASCII, whitespace or part of a UTF-8 character
+fn printable_ratio(bytes: &[u8]) -> f64 {
+    let printable = bytes.iter().filter(|&&b| b.is_ascii_graphic() || b.is_ascii_whitespace() || b >= 0x80).count();
+    printable as f64 / bytes.len().max(1) as f64
+}
+
+// Every regular file under root in --traversal order, reading each directory in name order.
+// Depth first reaches a directory's files right where the directory sorts, breadth first
+// lists every file of one level before going a level deeper. Symlinked directories are not
+// followed, so a link pointing back up the tree can't send the walk in circles. Directories
+// that can't be read are reported on stderr and counted in errors.
+fn collect_files(root: &Path, config: &Config, errors: &mut usize) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if root.is_file() {
+        files.push(root.to_path_buf());
+        return files;
+    }
+
+    // Paths still to visit, each with whether it is a directory to read
+    let mut pending = VecDeque::from([(root.to_path_buf(), true)]);
+    while let Some((path, is_dir)) = pending.pop_front() {
+        if !is_dir {
+            if metadata_matches(config, &path) {
+                files.push(path);
+            }
+            continue;
+        }
+
+        let mut entries = match fs::read_dir(&path).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                *errors += 1;
+                continue;
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut children = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    eprintln!("{}: {}", path.display(), err);
+                    *errors += 1;
+                    continue;
+                }
+            };
+            if file_type.is_dir() {
+                children.push((path, true));
+            } else if file_type.is_file() || (file_type.is_symlink() && path.is_file()) {
+                children.push((path, false));
+            }
+        }
+        match config.traversal {
+            // Ahead of everything else, so they come before the directory's later siblings
+            Traversal::DepthFirst => children.into_iter().rev().for_each(|child| pending.push_front(child)),
+            // Behind everything else, so the rest of this level comes first
+            Traversal::BreadthFirst => pending.extend(children),
+        }
+    }
+
+    files
+}
+
+// Whether a file passes --perm-match and --owner. Files whose metadata can't be read are
+// kept so that searching them reports the error.
+#[cfg(unix)]
+fn metadata_matches(config: &Config, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    if config.perm_match.is_none() && config.owner.is_none() {
+        return true;
+    }
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            config.perm_match.is_none_or(|mode| metadata.mode() & 0o7777 == mode)
+                && config.owner.is_none_or(|uid| metadata.uid() == uid)
+        }
+        Err(_) => true,
+    }
+}
+
+// Config::build rejects --perm-match and --owner elsewhere
+#[cfg(not(unix))]
+fn metadata_matches(_config: &Config, _path: &Path) -> bool {
+    true
+}
+
+// Searches one file's contents and writes its matches, returning how many lines matched
+fn search_contents(
+    sink: &mut Sink<'_>,
+    config: &Config,
+    pattern: &Pattern,
+    path: &str,
+    contents: &str,
+    offsets: &mut Vec<usize>,
+) -> Result<usize, Box<dyn Error>> {
+    let highlight = config.highlight();
+    let shown_path = if config.shows_paths() { Some(path) } else { None };
+
+    if config.paragraph {
+        if let Sink::Text(out, _) = sink {
+            return Ok(print_paragraphs(out, config, pattern, shown_path, contents, &highlight)?);
+        }
+    }
+
+    let limit = config.match_limit();
+    let results = if config.in_comments || config.in_strings {
+        let Some(syntax) = regions::syntax_for(path) else {
+            // A tree mixes languages, so only a single unknown file is an error
+            if config.shows_paths() {
+                return Ok(0);
+            }
+            return Err("--in-comments/--in-strings: unrecognized language for this file extension".into());
+        };
+        let mut wanted = Vec::new();
+        if config.in_comments {
+            wanted.push(Region::Comment);
+        }
+        if config.in_strings {
+            wanted.push(Region::Str);
+        }
+        region_search(pattern, contents, syntax, &wanted, config.invert, limit)
+    } else {
+        line_search(pattern, contents, config.invert, limit)
+    };
+
+    let total_matches = results.len();
+
+    if config.lists_files() {
+        write_file_name(sink, config, path, total_matches)?;
+        return Ok(total_matches);
+    }
+    if config.count {
+        write_count(sink, shown_path, total_matches)?;
+        return Ok(total_matches);
+    }
+    if config.before > 0 || config.after > 0 {
+        if let Sink::Text(out, grouped) = sink {
+            // Context needs the neighbouring lines, so these matches are written all at once
+            offsets.extend(results.iter().filter_map(|found| first_match_column(config, found)));
+            let shown = config.summarize_after.unwrap_or(total_matches).min(total_matches);
+            write_with_context(out, grouped, config, shown_path, contents, &results[..shown], &highlight)?;
+            write_summary(sink, config, total_matches)?;
+            return Ok(total_matches);
+        }
+    }
+
+    for (n, found) in results.iter().enumerate() {
+        offsets.extend(first_match_column(config, found));
+        write_match(sink, config, path, n, found, &highlight)?;
+    }
+    write_summary(sink, config, total_matches)?;
+    Ok(total_matches)
+}
+
+// Whether matches can be found a line at a time. Paragraphs, comment and string regions
+// and -A/-B context all need to see more than the line itself.
+fn can_stream(config: &Config) -> bool {
+    !config.paragraph && !config.in_comments && !config.in_strings && config.before == 0 && config.after == 0
+}
+
+// Reads lines one by one, calling f with every match, so a
+// file never has to be held in memory. Stops after `limit` matches and returns how many.
+fn for_each_match(
+    mut reader: impl BufRead,
+    pattern: &Pattern,
+    invert: bool,
+    limit: usize,
+    mut f: impl FnMut(LineMatch) -> Result<(), Box<dyn Error>>,
+) -> Result<usize, Box<dyn Error>> {
+    let mut matches = 0;
+    let mut line = String::new();
+    let mut line_number = 0;
+
+    while matches < limit {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        // Strip the ending the same way str::lines does
+        let text = line.strip_suffix('\n').unwrap_or(&line);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        if pattern.is_match(text) != invert {
+            f(LineMatch::new(pattern, invert, line_number, text))?;
+            matches += 1;
+        }
+    }
+
+    Ok(matches)
+}
+
+// Searches one file read from reader. Line-by-line modes stream it, the rest read it whole.
+fn search_reader(
+    sink: &mut Sink<'_>,
+    config: &Config,
+    pattern: &Pattern,
+    path: &str,
+    mut reader: impl BufRead,
+    offsets: &mut Vec<usize>,
+) -> Result<usize, Box<dyn Error>> {
+    if !can_stream(config) {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        return search_contents(sink, config, pattern, path, &contents, offsets);
+    }
+
+    let highlight = config.highlight();
+    let mut n = 0;
+    let total_matches = for_each_match(reader, pattern, config.invert, config.match_limit(), |found| {
+        offsets.extend(first_match_column(config, &found));
+        if !config.count && !config.lists_files() {
+            write_match(sink, config, path, n, &found, &highlight)?;
+        }
+        n += 1;
+        Ok(())
+    })?;
+
+    if config.lists_files() {
+        write_file_name(sink, config, path, total_matches)?;
+    } else if config.count {
+        let shown_path = if config.shows_paths() { Some(path) } else { None };
+        write_count(sink, shown_path, total_matches)?;
+    } else {
+        write_summary(sink, config, total_matches)?;
+    }
+    Ok(total_matches)
+}
+
+// The column where the first match of line starts, collected for --offset-histogram
+fn first_match_column(config: &Config, found: &LineMatch) -> Option<usize> {
+    if !config.offset_histogram {
+        return None;
+    }
+    let &(start, _) = found.ranges.first()?;
+    Some(found.line[..start].chars().count())
+}
+
+// Only the number of matching lines, one `path: N` line per file with -r
+fn write_count(sink: &mut Sink<'_>, shown_path: Option<&str>, total_matches: usize) -> io::Result<()> {
+    if let Sink::Text(out, _) = sink {
+        match shown_path {
+            Some(path) => writeln!(out, "{}: {}", path, total_matches)?,
+            None => writeln!(out, "{}", total_matches)?,
+        }
+    }
+    Ok(())
+}
+
+// Prints the path for -l when the file matched, or for -L when it didn't
+fn write_file_name(sink: &mut Sink<'_>, config: &Config, path: &str, total_matches: usize) -> io::Result<()> {
+    if let Sink::Text(out, _) = sink {
+        if (total_matches > 0) == config.files_with_matches {
+            writeln!(out, "{}{}{}", config.paint(MAGENTA), path, config.paint(RESET))?;
+        }
+    }
+    Ok(())
+}
+
+// With --summarize-after only the first N matches are printed, the rest are just counted
+fn write_summary(sink: &mut Sink<'_>, config: &Config, total_matches: usize) -> io::Result<()> {
+    let Sink::Text(out, _) = sink else { return Ok(()) };
+    let Some(shown) = config.summarize_after else { return Ok(()) };
+    if shown < total_matches {
+        writeln!(out, "... and {} more matches", total_matches - shown)?;
+    }
+    Ok(())
+}
+
+// Writes the n-th (0-based) matching line of a file to the sink
+fn write_match(
+    sink: &mut Sink<'_>,
+    config: &Config,
+    path: &str,
+    n: usize,
+    found: &LineMatch,
+    highlight: &str,
+) -> Result<(), Box<dyn Error>> {
+    let shown_path = if config.shows_paths() { Some(path) } else { None };
+    let &LineMatch { line_number, line, ref ranges } = found;
+    match sink {
+        Sink::JsonArray(_, records) => {
+            records.push(MatchRecord { path: path.to_string(), line_number, line: line.to_string() });
+        }
+        Sink::Csv(out) => {
+            let column = ranges
+                .first()
+                .map(|&(start, _)| line[..start].chars().count() + 1)
+                .unwrap_or(1);
+            let mut writer = csv::Writer::from_writer(&mut **out);
+            writer.write_record([path, &line_number.to_string(), &column.to_string(), line])?;
+            writer.flush()?;
+        }
+        Sink::Json(out) => {
+            // Newline-delimited, so a consumer can handle each match as soon as it arrives
+            let record = JsonRecord {
+                path: path.to_string(),
+                line_number,
+                line: line.to_string(),
+                matches: ranges.iter().map(|&(start, end)| ByteRange { start, end }).collect(),
+            };
+            serde_json::to_writer(&mut *out, &record)?;
+            writeln!(out)?;
+        }
+        Sink::Emit(stream) => {
+            // Stream one JSON record per match to the consumer
+            let record = MatchRecord { path: path.to_string(), line_number, line: line.to_string() };
+            serde_json::to_writer(&mut *stream, &record)?;
+            writeln!(stream)?;
+        }
+        // Files are only ranked as a whole, see Sink::absorb
+        Sink::Ranked(..) => {}
+        Sink::Text(out, _) => {
+            if config.summarize_after.is_some_and(|shown| n >= shown) {
+                // Past --summarize-after, only counted for the note written by write_summary
+            } else if let (Some(chars), true) = (config.context_chars, config.invert) {
+                // An inverted match has nothing to center on, so its first characters stand in
+                let end = line.char_indices().nth(chars).map_or(line.len(), |(i, _)| i);
+                let cut = if end < line.len() { "…" } else { "" };
+                write_prefix(out, config, shown_path, line_number, ':')?;
+                writeln!(out, "{}{}", &line[..end], cut)?;
+            } else if let Some(chars) = config.context_chars {
+                // Print a window around each match instead of the whole line
+                for &(start, end) in ranges {
+                    let (before, matched, after) = context_window(line, start, end, chars);
+                    write_prefix(out, config, shown_path, line_number, ':')?;
+                    writeln!(out, "{}{}{}{}{}", before, highlight, matched, config.paint(RESET), after)?;
+                }
+            } else if config.only_matching {
+                // Each non-overlapping match on its own line, left to right like grep -o.
+                // An inverted match has nothing matched to print.
+                if !config.invert {
+                    for &(start, end) in ranges {
+                        write_prefix(out, config, shown_path, line_number, ':')?;
+                        writeln!(out, "{}{}{}", highlight, &line[start..end], config.paint(RESET))?;
+                    }
+                }
+            } else {
+                write_match_line(out, config, shown_path, found, highlight)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a finished search found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    /// How many lines matched, over every file searched.
+    pub matches: usize,
+    /// Whether a file or directory couldn't be read. The search went on without it and
+    /// the error was reported on stderr.
+    pub had_errors: bool,
+}
+
+/// Compiles the query the way `run` would, without reading any file or standard input.
+/// The error says where a regex fails to parse.
+pub fn check_pattern(config: &Config) -> Result<(), Box<dyn Error>> {
+    Pattern::build(config).map(|_| ())
+}
+
+/// Searches the configured files (or every file under them with -r), prints the
+/// matching lines to stdout and returns how many lines matched.
+pub fn run(config: Config) -> Result<Outcome, Box<dyn Error>> {
+    run_to(config, &mut io::stdout().lock())
+}
+
+/// Like `run`, but writes the results to `writer` instead of stdout.
+pub fn run_to(config: Config, writer: &mut impl Write) -> Result<Outcome, Box<dyn Error>> {
+    let pattern = Pattern::build(&config)?;
+    let out: Box<dyn Write + '_> = match config.output_encoding {
+        Some(encoding) => Box::new(EncodingWriter::new(writer, encoding)),
+        None => Box::new(writer),
+    };
+    let mut sink = Sink::new(&config, out)?;
+    let mut offsets = Vec::new();
+    let mut stats = Stats {
+        lists_files: config.lists_files(),
+        densities: config.density.then(Vec::new),
+        ..Default::default()
+    };
+
+    let stdin_only = [String::from("-")];
+    let paths = if config.file_paths.is_empty() { &stdin_only[..] } else { &config.file_paths[..] };
+
+    let mut progress = Progress::new(&config)?;
+    let mut out_of_time = false;
+    for file_path in paths {
+        // Within the files -r finds, search_files keeps an eye on the deadline itself
+        if out_of_time || progress.out_of_time() {
+            out_of_time = true;
+            break;
+        }
+        if file_path == "-" && config.buffers_files() {
+            let output = search_to_buffer(&config, &pattern, sink.kind(), STDIN_LABEL, io::stdin().lock())?;
+            write_file_output(&mut sink, &config, None, output, &mut offsets, &mut stats)?;
+        } else if file_path == "-" {
+            // Piped input is searched and highlighted just like a file
+            let matches = search_reader(&mut sink, &config, &pattern, STDIN_LABEL, io::stdin().lock(), &mut offsets)?;
+            stats.add_file(matches);
+        } else if config.recursive {
+            let files = collect_files(Path::new(file_path), &config, &mut stats.errors);
+            out_of_time = search_files(&mut sink, &config, &pattern, &files, &mut offsets, &mut stats, &mut progress)?;
+        } else if config.buffers_files() {
+            let file = [PathBuf::from(file_path)];
+            out_of_time = search_files(&mut sink, &config, &pattern, &file, &mut offsets, &mut stats, &mut progress)?;
+        } else if is_binary(Path::new(file_path), config.text_if_printable) {
+            continue;
+        } else {
+            let searched = fs::File::open(file_path).map_err(Into::into).and_then(|file| {
+                let shown = config.output_path(file_path);
+                search_reader(&mut sink, &config, &pattern, &shown, BufReader::new(file), &mut offsets)
+            });
+            match searched {
+                Ok(matches) => {
+                    stats.add_file(matches);
+                    stats.add_density(Path::new(file_path), matches);
+                }
+                // With several files the rest are still worth searching
+                Err(err) if paths.len() > 1 => {
+                    eprintln!("{}: {}", file_path, err);
+                    stats.errors += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    if config.offset_histogram {
+        print_offset_histogram(&offsets);
+    }
+    sink.finish()?;
+    progress.save()?;
+    if out_of_time {
+        eprintln!("time budget exceeded, the results are partial");
+    }
+    // After the results, so the summary follows everything written to stdout
+    if config.stats {
+        eprintln!("{stats}");
+    }
+    Ok(Outcome { matches: stats.matches, had_errors: stats.errors > 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Config::build arguments with the program name in front
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("miniGrep").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    // Config::build as if IGNORE_CASE were unset
+    fn build(list: &[&str]) -> Result<Config, BuildError> {
+        Config::build_with_env(&args(list), false)
+    }
+
+    // The message of an invalid argument list
+    fn build_error(list: &[&str]) -> String {
+        match build(list) {
+            Err(BuildError::Invalid(message)) => message,
+            other => panic!("expected an invalid-arguments error, got {other:?}"),
+        }
+    }
+
+    // Runs a search with color off, returning what it printed and the match count
+    fn run_args(list: &[&str]) -> (String, usize) {
+        let mut list = list.to_vec();
+        list.extend(["--color", "never"]);
+        let mut out = Vec::new();
+        let total = run_to(build(&list).unwrap(), &mut out).unwrap().matches;
+        (String::from_utf8(out).unwrap(), total)
+    }
+
+    // A directory of its own for each test's files, emptied first
+    fn fixture_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minigrep-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fixture(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn help_flag() {
+        assert_eq!(build(&["-h"]).unwrap_err(), BuildError::Help);
+        assert_eq!(build(&["-q", "foo", "--help"]).unwrap_err(), BuildError::Help);
+        // As the value of -q it is the query, found by the same skipping as other values
+        assert_eq!(build(&["-q", "-h", "-p", "notes.txt"]).unwrap().query, "-h");
+    }
+
+    #[test]
+    fn ignore_case_forms() {
+        let forms: &[&[&str]] = &[
+            &["-q", "foo", "-i"],
+            &["-q", "foo", "--ignore-case"],
+            &["-i", "-q=foo"],
+            &["-qfoo", "-i"],
+            &["-iq", "foo"],
+            &["-iqfoo"],
+            &["-ni", "-q", "foo"],
+            &["-q=foo", "-p=notes.txt", "-i"],
+        ];
+        for form in forms {
+            let config = build(form).unwrap();
+            assert!(config.ignore_case(), "{form:?}");
+            assert_eq!(config.query(), "foo", "{form:?}");
+        }
+        assert!(!build(&["-q", "foo"]).unwrap().ignore_case());
+    }
+
+    #[test]
+    fn ignore_case_env() {
+        assert!(Config::build_with_env(&args(&["-q", "foo"]), true).unwrap().ignore_case());
+        assert!(!Config::build_with_env(&args(&["-q", "foo"]), false).unwrap().ignore_case());
+        // Smart case can't turn it back off
+        assert!(Config::build_with_env(&args(&["-q", "Foo", "-S"]), true).unwrap().ignore_case());
+    }
+
+    #[test]
+    fn bundled_flags() {
+        let config = build(&["-inq", "foo", "-pnotes.txt", "todo.txt", "-C3"]).unwrap();
+        assert!(config.ignore_case && config.line_number);
+        assert_eq!(config.file_paths(), ["notes.txt", "todo.txt"]);
+        assert_eq!((config.before, config.after), (3, 3));
+        // Values of flags are never split
+        assert_eq!(build(&["-q", "-in"]).unwrap().query(), "-in");
+        assert_eq!(build(&["-m", "2", "-q", "-vq"]).unwrap().query(), "-vq");
+        // Nor are bundles with a letter that isn't a flag
+        assert_eq!(build_error(&["-q", "foo", "-ix"]), "unknown flag: -ix");
+    }
+
+    #[test]
+    fn unknown_flag() {
+        assert_eq!(build_error(&["-q", "foo", "--foo"]), "unknown flag: --foo");
+        assert_eq!(build_error(&["--foo", "-q", "foo"]), "unknown flag: --foo");
+    }
+
+    #[test]
+    fn stray_argument() {
+        assert_eq!(build_error(&["-q", "foo", "notes.txt"]), "unexpected argument: notes.txt");
+        assert_eq!(build_error(&["notes.txt", "-q", "foo"]), "unexpected argument: notes.txt");
+        assert_eq!(build_error(&["-q", "foo", "-m", "2", "3"]), "unexpected argument: 3");
+        // Everything after -p up to the next flag is a path
+        assert_eq!(build(&["-q", "foo", "-p", "a.txt", "b.txt", "-n"]).unwrap().file_paths(), ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(build_error(&["-p", "notes.txt", "-q"]), "missing value for -q");
+        assert_eq!(build_error(&["-q", "foo", "-p"]), "missing value for -p");
+        // A flag after -p isn't one of its paths
+        assert_eq!(build_error(&["-q", "foo", "-p", "-n"]), "missing value for -p");
+        assert_eq!(build_error(&["-p", "notes.txt"]), "missing query, pass one with -q");
+    }
+
+    #[test]
+    fn streaming_matches_the_whole_file_search() {
+        let contents = "Foo bar\r\nfood\nbar\nfoo, again\n";
+        let patterns = [
+            Pattern::Literal { query: "foo", ignore_case: false, whole_word: false },
+            Pattern::Literal { query: "foo", ignore_case: true, whole_word: false },
+            Pattern::Literal { query: "foo", ignore_case: true, whole_word: true },
+            Pattern::Regex(Regex::new("^f").unwrap()),
+        ];
+        for pattern in &patterns {
+            for (invert, limit) in [(false, usize::MAX), (true, usize::MAX), (false, 1)] {
+                let mut streamed = Vec::new();
+                for_each_match(contents.as_bytes(), pattern, invert, limit, |found| {
+                    streamed.push((found.line_number, found.line.to_string(), found.ranges));
+                    Ok(())
+                })
+                .unwrap();
+                let whole: Vec<_> = line_search(pattern, contents, invert, limit)
+                    .into_iter()
+                    .map(|found| (found.line_number, found.line.to_string(), found.ranges))
+                    .collect();
+                assert_eq!(streamed, whole);
+            }
+        }
+        let (out, total) = run_args(&["-q", "foo", "-i", "-p", &fixture(&fixture_dir("streaming"), "a.txt", contents)]);
+        assert_eq!(total, 3);
+        assert_eq!(out.lines().count(), 3);
+    }
+
+    #[test]
+    fn ignore_case_highlights_the_lowercase_word() {
+        let path = fixture(&fixture_dir("ignore-case-highlight"), "a.txt", "say hello world\n");
+        let mut out = Vec::new();
+        run_to(build(&["-q", "WORLD", "-i", "-p", &path, "--color", "always"]).unwrap(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "say hello \x1b[31m\x1b[4mworld\x1b[0m \n");
+    }
+
+    #[test]
+    fn max_count_stops_after_n_matches() {
+        let path = fixture(&fixture_dir("max-count"), "a.txt", &"foo\n".repeat(10));
+        let (out, total) = run_args(&["-q", "foo", "-p", &path, "-m", "3"]);
+        assert_eq!(total, 3);
+        assert_eq!(out.lines().count(), 3);
+    }
+
+    #[test]
+    fn smart_case() {
+        let path = fixture(&fixture_dir("smart-case"), "a.txt", "Foo\nfoo\nFOO\n");
+        // All lowercase ignores case, any uppercase letter makes it exact
+        assert_eq!(run_args(&["-q", "foo", "-p", &path, "-S"]).1, 3);
+        assert_eq!(run_args(&["-q", "Foo", "-p", &path, "--smart-case"]), ("Foo \n".to_string(), 1));
+        assert_eq!(run_args(&["-q", "Foo", "-p", &path, "-S", "-i"]).1, 3);
+    }
+
+    #[test]
+    fn fixed_strings_match_dots_literally() {
+        let path = fixture(&fixture_dir("fixed-strings"), "a.txt", "1.2.3\n1x2x3\n");
+        assert_eq!(run_args(&["-q", "1.2.3", "-p", &path, "--regex"]).1, 2);
+        assert_eq!(run_args(&["-q", "1.2.3", "-p", &path, "-F"]), ("1.2.3 \n".to_string(), 1));
+        assert_eq!(run_args(&["-q", "1.2.3", "-p", &path]).1, 1);
+        assert_eq!(
+            build_error(&["-q", "1.2.3", "--regex", "-F"]),
+            "--regex and -F/--fixed-strings conflict, pass only one of them"
+        );
+    }
+
+    #[test]
+    fn files_with_and_without_matches() {
+        let dir = fixture_dir("list-files");
+        let a = fixture(&dir, "a.txt", "foo\nfoo\n");
+        let b = fixture(&dir, "b.txt", "bar\n");
+        let c = fixture(&dir, "sub/c.txt", "foo\n");
+        let dir = dir.to_string_lossy().into_owned();
+
+        let (out, _) = run_args(&["-q", "foo", "-r", "-p", &dir, "-l"]);
+        let mut listed: Vec<_> = out.lines().collect();
+        listed.sort();
+        assert_eq!(listed, [a.as_str(), c.as_str()]);
+
+        let (out, _) = run_args(&["-q", "foo", "-r", "-p", &dir, "--files-without-match"]);
+        assert_eq!(out, format!("{b}\n"));
+
+        assert_eq!(
+            build_error(&["-q", "foo", "-l", "-L"]),
+            "-l/--files-with-matches and -L/--files-without-match conflict, pass only one of them"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinks_prints_the_real_path() {
+        let dir = fixture_dir("resolve-symlinks");
+        let real = fixture(&dir, "real/f.txt", "foo\n");
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let linked = dir.join("link/f.txt").to_string_lossy().into_owned();
+        let real = fs::canonicalize(real).unwrap().to_string_lossy().into_owned();
+
+        let (out, _) = run_args(&["-q", "foo", "-p", &linked, "--csv", "--no-header"]);
+        assert_eq!(out, format!("{linked},1,1,foo\n"));
+        let (out, _) = run_args(&["-q", "foo", "-p", &linked, "--csv", "--no-header", "--resolve-symlinks"]);
+        assert_eq!(out, format!("{real},1,1,foo\n"));
+        // -r on the link finds the file under it, and prints where it really is
+        let link_root = dir.join("link").to_string_lossy().into_owned();
+        let (out, _) = run_args(&["-q", "foo", "-r", "-p", &link_root, "-l", "--resolve-symlinks"]);
+        assert_eq!(out, format!("{real}\n"));
+    }
+
+    #[test]
+    fn context_groups() {
+        let dir = fixture_dir("context");
+        let contents = "foo\n2\n3\nfoo\n5\n6\n7\n8\nfoo\n10\n";
+        let first = fixture(&dir, "a.txt", contents);
+        let second = fixture(&dir, "b.txt", "1\nfoo\n");
+
+        // Lines 1 and 4 are close enough to share a group, line 9 gets its own
+        let (out, total) = run_args(&["-q", "foo", "-p", &first, "-n", "-C", "1"]);
+        assert_eq!(total, 3);
+        assert_eq!(out, "1:foo \n2-2\n3-3\n4:foo \n5-5\n--\n8-8\n9:foo \n10-10\n");
+        let (out, _) = run_args(&["-q", "foo", "-p", &first, "-A", "1"]);
+        assert_eq!(out, "foo \n2\n--\nfoo \n5\n--\nfoo \n10\n");
+
+        // Groups from different files are divided as well, with or without -r
+        let (out, _) = run_args(&["-q", "foo", "-p", &first, &second, "-B", "1"]);
+        let expected = format!(
+            "{first}:foo \n--\n{first}-3\n{first}:foo \n--\n{first}-8\n{first}:foo \n--\n{second}-1\n{second}:foo \n"
+        );
+        assert_eq!(out, expected);
+        let (out, _) = run_args(&["-q", "foo", "-r", "-p", &dir.to_string_lossy(), "-B", "1"]);
+        assert_eq!(out, expected);
+
+        assert_eq!(build_error(&["-q", "foo", "-C", "1", "-o"]), "-A/-B/-C and -o/--only-matching conflict, pass only one of them");
+        assert_eq!(
+            build_error(&["-q", "foo", "-A", "1", "--context-chars", "3"]),
+            "-A/-B/-C and --context-chars conflict, pass only one of them"
+        );
+    }
+
+    #[test]
+    fn context_merge() {
+        let dir = fixture_dir("context-merge");
+        let path = fixture(&dir, "a.txt", "1\nfoo\n3\n4\n5\n6\nfoo\n8\n");
+
+        // The groups around lines 2 and 7 leave lines 4 and 5 out
+        let separate = "1-1\n2:foo \n3-3\n--\n6-6\n7:foo \n8-8\n";
+        let merged = "1-1\n2:foo \n3-3\n4-4\n5-5\n6-6\n7:foo \n8-8\n";
+        assert_eq!(run_args(&["-q", "foo", "-p", &path, "-n", "-C", "1"]).0, separate);
+        assert_eq!(run_args(&["-q", "foo", "-p", &path, "-n", "-C", "1", "--context-merge", "1"]).0, separate);
+        assert_eq!(run_args(&["-q", "foo", "-p", &path, "-n", "-C", "1", "--context-merge", "2"]).0, separate);
+        assert_eq!(run_args(&["-q", "foo", "-p", &path, "-n", "-C", "1", "--context-merge", "3"]).0, merged);
+
+        assert_eq!(build_error(&["-q", "foo", "--context-merge", "3"]), "--context-merge needs -A/-B/-C");
+    }
+
+    #[test]
+    fn validate_regex() {
+        let valid = build(&["-q", "fo+|ba[rz]", "--validate-regex"]).unwrap();
+        assert!(valid.validate_regex() && valid.regex);
+        assert!(check_pattern(&valid).is_ok());
+
+        let err = check_pattern(&build(&["-q", "fo(o", "--validate-regex"]).unwrap()).unwrap_err().to_string();
+        assert!(err.starts_with("invalid regex pattern \"fo(o\""), "{err}");
+        assert!(err.contains("unclosed group"), "{err}");
+
+        assert_eq!(
+            build_error(&["-q", "foo", "--validate-regex", "-F"]),
+            "--validate-regex and -F/--fixed-strings conflict, pass only one of them"
+        );
+    }
+
+    #[test]
+    fn match_count_filters() {
+        let dir = fixture_dir("match-count-filters");
+        let one = fixture(&dir, "1.txt", "foo\n");
+        let two = fixture(&dir, "2.txt", "foo\nfoo\n");
+        let three = fixture(&dir, "3.txt", "foo\nbar\nfoo\nfoo\n");
+        let files = |extra: &[&str]| run_args(&[&["-q", "foo", "-p", &one, &two, &three, "-l"], extra].concat()).0;
+
+        assert_eq!(files(&["--min-matches", "2"]), format!("{two}\n{three}\n"));
+        assert_eq!(files(&["--min-matches", "3"]), format!("{three}\n"));
+        assert_eq!(files(&["--min-matches", "4"]), "");
+        assert_eq!(files(&["--max-matches-per-file", "2"]), format!("{one}\n{two}\n"));
+        assert_eq!(files(&["--min-matches", "2", "--max-matches-per-file", "2"]), format!("{two}\n"));
+
+        // Line output and counts are left out whole, and so are their matches from the total
+        let (out, total) = run_args(&["-q", "foo", "-p", &one, &two, &three, "--min-matches", "3"]);
+        assert_eq!((out, total), (format!("{three}:foo \n{three}:foo \n{three}:foo \n"), 3));
+        let (out, _) = run_args(&["-q", "foo", "-p", &one, &two, &three, "-c", "--max-matches-per-file", "1"]);
+        assert_eq!(out, format!("{one}: 1\n"));
+        let (out, _) = run_args(&["-q", "foo", "-r", "-p", &dir.to_string_lossy(), "-c", "--min-matches", "2"]);
+        assert_eq!(out, format!("{two}: 2\n{three}: 3\n"));
+
+        assert_eq!(
+            build_error(&["-q", "foo", "-m", "1", "--min-matches", "2"]),
+            "--min-matches and -m/--max-count conflict, pass only one of them"
+        );
+        assert_eq!(
+            build_error(&["-q", "foo", "-L", "--max-matches-per-file", "2"]),
+            "--max-matches-per-file and -L/--files-without-match conflict, pass only one of them"
+        );
+        assert_eq!(
+            build_error(&["-q", "foo", "--min-matches", "3", "--max-matches-per-file", "2"]),
+            "--min-matches can't be more than --max-matches-per-file"
+        );
+    }
+
+    #[test]
+    fn keep_going_skips_a_failed_file() {
+        let dir = fixture_dir("keep-going");
+        let first = fixture(&dir, "a.txt", "foo\n");
+        let failing = fixture(&dir, "minigrep-panics.txt", "foo\n");
+        let last = fixture(&dir, "z.txt", "foo\n");
+
+        let config = build(&["-q", "foo", "-p", &first, &failing, &last, "--keep-going", "--color", "never"]).unwrap();
+        let mut out = Vec::new();
+        let outcome = run_to(config, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{first}:foo \n{last}:foo \n"));
+        assert_eq!(outcome.matches, 2);
+        assert!(outcome.had_errors);
+
+        let (out, total) = run_args(&["-q", "foo", "-r", "-p", &dir.to_string_lossy(), "--keep-going"]);
+        assert_eq!((out, total), (format!("{first}:foo \n{last}:foo \n"), 2));
+    }
+
+    #[test]
+    fn time_budget() {
+        assert_eq!(parse_duration("2s"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("3"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_duration("2 days"), None);
+        assert_eq!(parse_duration("-1s"), None);
+
+        let dir = fixture_dir("time-budget");
+        let first = fixture(&dir, "a.txt", "foo\n");
+        let second = fixture(&dir, "b.txt", "foo\n");
+        let dir = dir.to_str().unwrap();
+        // The budget is used up before the first file
+        assert_eq!(run_args(&["-q", "foo", "-p", &first, &second, "--time-budget", "0s"]), (String::new(), 0));
+        assert_eq!(run_args(&["-q", "foo", "-r", "-p", dir, "--time-budget", "0ms"]), (String::new(), 0));
+        assert_eq!(run_args(&["-q", "foo", "-r", "-p", dir, "--time-budget", "1h"]).1, 2);
+
+        assert_eq!(build_error(&["-q", "foo", "--time-budget", "soon"]), "--time-budget expects a duration like 2s or 500ms");
+    }
+
+    #[test]
+    fn rank_by_matches_and_recency() {
+        let dir = fixture_dir("rank");
+        let old = fixture(&dir, "old.txt", "foo\nfoo\nfoo\nfoo\n");
+        let new = fixture(&dir, "new.txt", "foo\nfoo\nfoo\n");
+        let newest = fixture(&dir, "newest.txt", "foo\n");
+        let unmatched = fixture(&dir, "unmatched.txt", "bar\n");
+        let days_ago = |days: u64| SystemTime::now() - Duration::from_secs(days * 86_400);
+        for (path, modified) in [(&old, days_ago(29)), (&new, days_ago(1)), (&newest, SystemTime::now()), (&unmatched, days_ago(1))] {
+            fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        }
+        let dir = dir.to_str().unwrap();
+
+        // 4 / 30 for the old file, 3 / 2 and 1 / 1 for the others
+        let (out, total) = run_args(&["-q", "foo", "-r", "-p", dir, "--rank"]);
+        assert_eq!(out, format!("1.50  {new}\n1.00  {newest}\n0.13  {old}\n"));
+        assert_eq!(total, 8);
+        // Without any weight for age it is down to the counts
+        let (out, _) = run_args(&["-q", "foo", "-p", &newest, &new, &old, "--rank", "--recency-weight", "0"]);
+        assert_eq!(out, format!("4.00  {old}\n3.00  {new}\n1.00  {newest}\n"));
+
+        assert_eq!(build_error(&["-q", "foo", "--rank", "-c"]), "-c/--count and --rank conflict, pass only one of them");
+        assert_eq!(build_error(&["-q", "foo", "--recency-weight", "2"]), "--recency-weight needs --rank");
+    }
+
+    #[test]
+    fn checkpoint_and_resume() {
+        let dir = fixture_dir("checkpoint");
+        let first = fixture(&dir, "a.txt", "foo\n");
+        let second = fixture(&dir, "b.txt", "foo\n");
+        let checkpoint = fixture_dir("checkpoint-file").join("progress").to_string_lossy().into_owned();
+        let _ = fs::remove_file(&checkpoint);
+        let dir = dir.to_str().unwrap();
+
+        // A run that was stopped after its first file
+        run_args(&["-q", "foo", "-p", &first, "--checkpoint", &checkpoint]);
+        assert_eq!(fs::read_to_string(&checkpoint).unwrap(), format!("{first}\n"));
+
+        // Resuming skips it and adds the rest
+        let resume = ["-q", "foo", "-r", "-p", dir, "--continue-from", &checkpoint, "--checkpoint", &checkpoint];
+        assert_eq!(run_args(&resume), (format!("{second}:foo \n"), 1));
+        assert_eq!(fs::read_to_string(&checkpoint).unwrap(), format!("{first}\n{second}\n"));
+        assert_eq!(run_args(&resume), (String::new(), 0));
+
+        // Without --continue-from everything is searched again
+        assert_eq!(run_args(&["-q", "foo", "-r", "-p", dir, "--checkpoint", &checkpoint]).1, 2);
+    }
+
+    #[test]
+    fn binary_detection() {
+        assert!(!looks_binary("naïve text\n".as_bytes()));
+        assert!(looks_binary(b"ELF\0\x01"));
+        // Only the start of the file is checked
+        let late_nul = [&[b'a'; BINARY_SNIFF_LEN][..], b"\0"].concat();
+        assert!(!looks_binary(&late_nul));
+    }
+
+    #[test]
+    fn text_if_printable() {
+        let dir = fixture_dir("text-if-printable");
+        let mostly_text = fixture(&dir, "log.txt", &format!("{}\0foo\n", "text line\n".repeat(20)));
+        let binary = fixture(&dir, "blob.bin", &format!("{}foo\n", "\0\x01".repeat(100)));
+
+        assert_eq!(run_args(&["-q", "foo", "-p", &mostly_text]).1, 0);
+        assert_eq!(run_args(&["-q", "foo", "-p", &mostly_text, "--text-if-printable", "0.9"]).1, 1);
+        assert_eq!(run_args(&["-q", "foo", "-p", &binary, "--text-if-printable", "0.9"]).1, 0);
+        assert_eq!(run_args(&["-q", "foo", "-p", &binary, "--text-if-printable", "0"]).1, 1);
+        assert!(printable_ratio(b"ab\0\0") == 0.5 && printable_ratio("é\n".as_bytes()) == 1.0);
+
+        assert_eq!(
+            build_error(&["-q", "foo", "--text-if-printable", "95"]),
+            "--text-if-printable expects a ratio between 0 and 1"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn metadata_filters() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = fixture_dir("metadata-filters");
+        let open = fixture(&dir, "open.txt", "foo\n");
+        let private = fixture(&dir, "private.txt", "foo\n");
+        fs::set_permissions(&open, fs::Permissions::from_mode(0o777)).unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o600)).unwrap();
+        let uid = fs::metadata(&open).unwrap().uid();
+        let dir = dir.to_str().unwrap();
+
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "-l", "--perm-match", "777"]).0, format!("{open}\n"));
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "--perm-match", "0600"]).1, 1);
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "--perm-match", "644"]).1, 0);
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "--owner", &uid.to_string()]).1, 2);
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "--owner", &(uid + 1).to_string()]).1, 0);
+        assert_eq!(user_id("root"), Some(0));
+
+        assert_eq!(build_error(&["-q", "foo", "-r", "--perm-match", "9"]), "--perm-match expects octal permission bits like 644");
+        assert_eq!(build_error(&["-q", "foo", "-r", "--owner", "no-such-user"]), "unknown user for --owner: no-such-user");
+        assert_eq!(build_error(&["-q", "foo", "--owner", "root"]), "--perm-match and --owner need -r/--recursive");
+    }
+
+    #[test]
+    fn traversal_order() {
+        let dir = fixture_dir("traversal");
+        fs::create_dir_all(dir.join("a/deep")).unwrap();
+        let deep = fixture(&dir, "a/deep/1.txt", "foo\n");
+        let shallow_a = fixture(&dir, "a/shallow.txt", "foo\n");
+        let top = fixture(&dir, "b.txt", "foo\n");
+        let dir = dir.to_str().unwrap();
+
+        let dfs = run_args(&["-q", "foo", "-p", dir, "-r", "-l"]).0;
+        assert_eq!(dfs, format!("{deep}\n{shallow_a}\n{top}\n"));
+        assert_eq!(run_args(&["-q", "foo", "-p", dir, "-r", "-l", "--traversal", "dfs"]).0, dfs);
+        let bfs = run_args(&["-q", "foo", "-p", dir, "-r", "-l", "--traversal", "bfs"]).0;
+        assert_eq!(bfs, format!("{top}\n{shallow_a}\n{deep}\n"));
+
+        assert_eq!(build_error(&["-q", "foo", "--traversal", "random"]), "unknown traversal, expected dfs or bfs");
+    }
+
+    #[test]
+    fn stats_summary() {
+        let mut stats = Stats::default();
+        for matches in [2, 0, 1] {
+            stats.add_file(matches);
+        }
+        assert_eq!(stats.to_string(), "3 matches in 2 files (searched 3)");
+        stats.lists_files = true;
+        assert_eq!(stats.to_string(), "2 files matched (searched 3)");
+
+        assert_eq!(build_error(&["-q", "foo", "--density"]), "--density needs --stats");
+        assert_eq!(
+            build_error(&["-q", "foo", "--stats", "--density", "-l"]),
+            "--density and -l/--files-with-matches conflict, pass only one of them"
+        );
+    }
+
+    #[test]
+    fn highlight_style_codes() {
+        let blue = Some(HighlightColor::Blue);
+        assert_eq!(HighlightStyle::Underline.codes(None), "\x1b[31m\x1b[4m");
+        assert_eq!(HighlightStyle::Underline.codes(blue), "\x1b[34m\x1b[4m");
+        assert_eq!(HighlightStyle::Background.codes(None), "\x1b[41m");
+        assert_eq!(HighlightStyle::Background.codes(blue), "\x1b[44m");
+        assert_eq!(HighlightStyle::Bold.codes(None), "\x1b[31m\x1b[1m");
+        assert_eq!(HighlightStyle::Bold.codes(blue), "\x1b[34m\x1b[1m");
+        // Reverse keeps the terminal's own colors unless one is asked for
+        assert_eq!(HighlightStyle::Reverse.codes(None), "\x1b[7m");
+        assert_eq!(HighlightStyle::Reverse.codes(blue), "\x1b[34m\x1b[7m");
+    }
+
+    #[test]
+    fn csv_quotes_commas_and_quotes() {
+        let path = fixture(&fixture_dir("csv-quoting"), "a.txt", "say \"a, b\" here\nnone\n");
+        let (out, total) = run_args(&["-q", "\"a, b\"", "-p", &path, "--csv"]);
+        assert_eq!(total, 1);
+        let mut reader = csv::Reader::from_reader(out.as_bytes());
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(reader.headers().unwrap(), vec!["path", "line", "column", "text"]);
+        assert_eq!(rows, [csv::StringRecord::from(vec![path.as_str(), "1", "5", "say \"a, b\" here"])]);
+    }
+
+    #[test]
+    fn json_array_parses() {
+        let path = fixture(&fixture_dir("json-array"), "a.txt", "foo\nbar\nfoo \"quoted\"\n");
+        let (out, _) = run_args(&["-q", "foo", "-p", &path, "--json-array"]);
+        let records: Vec<serde_json::Value> = serde_json::from_str(&out).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["line_number"], 1);
+        assert_eq!(records[1]["line"], "foo \"quoted\"");
+        assert_eq!(records[1]["path"], path.as_str());
+
+        let (out, total) = run_args(&["-q", "baz", "-p", &path, "--json-array"]);
+        assert_eq!(total, 0);
+        assert!(serde_json::from_str::<Vec<serde_json::Value>>(&out).unwrap().is_empty());
+    }
+
+    #[test]
+    fn offset_buckets_count_columns() {
+        assert!(offset_buckets(&[]).is_empty());
+        assert_eq!(offset_buckets(&[0]), [1]);
+        let last = HISTOGRAM_BUCKET - 1;
+        assert_eq!(offset_buckets(&[0, last, HISTOGRAM_BUCKET, 3 * HISTOGRAM_BUCKET + 1]), [2, 1, 0, 1]);
+    }
+
+    #[test]
+    fn in_comments_after_a_quote_char_literal() {
+        let dir = fixture_dir("in-comments");
+        let path = fixture(&dir, "main.rs", "let c = '\"';\n// foo here\nlet foo = 1;\n");
+        let (output, total) = run_args(&["-q", "foo", "-p", &path, "--in-comments"]);
+        assert_eq!(total, 1);
+        assert_eq!(output, "// foo here \n"); // Words are printed one by one, each followed by a space
+    }
+
+    #[test]
+    fn in_comments_reports_only_the_comment_match() {
+        let dir = fixture_dir("in-comments-ranges");
+        let path = fixture(&dir, "main.rs", "let foo = 1; // foo here\n");
+        let search = |extra: &[&str]| run_args(&[&["-q", "foo", "-p", &path, "--in-comments"], extra].concat()).0;
+
+        assert_eq!(search(&["-o", "-n"]), "1:foo\n");
+        assert_eq!(search(&["--context-chars", "3"]), "…// foo he…\n");
+        let json: serde_json::Value = serde_json::from_str(&search(&["--json"])).unwrap();
+        assert_eq!(json["matches"], serde_json::json!([{ "start": 16, "end": 19 }]));
+        assert!(search(&["--csv", "--no-header"]).ends_with(",1,17,let foo = 1; // foo here\n"));
+
+        let mut out = Vec::new();
+        run_to(build(&["-q", "foo", "-p", &path, "--in-comments", "--color", "always"]).unwrap(), &mut out).unwrap();
+        let highlighted = format!("let foo = 1; // {RED}{UNDERLINE}foo{RESET} here \n");
+        assert_eq!(String::from_utf8(out).unwrap(), highlighted);
+    }
+
+    #[test]
+    fn in_strings() {
+        let dir = fixture_dir("in-strings");
+        let path = fixture(&dir, "main.py", "foo = 'foo'\nprint(foo)\n# 'foo' in a comment\n");
+        let (out, total) = run_args(&["-q", "foo", "-p", &path, "--in-strings", "-o", "-n"]);
+        assert_eq!(total, 1);
+        assert_eq!(out, "1:foo\n");
+        let (out, _) = run_args(&["-q", "foo", "-p", &path, "--in-strings", "--json"]);
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(json["matches"], serde_json::json!([{ "start": 7, "end": 10 }]));
+    }
+
+    #[test]
+    fn context_chars_window_on_a_long_line() {
+        let dir = fixture_dir("context-chars");
+        let line = format!("{}needle{}", "a".repeat(500), "b".repeat(500));
+        let path = fixture(&dir, "minified.js", &format!("{line}\nshort needle\n"));
+        let (output, total) = run_args(&["-q", "needle", "-p", &path, "--context-chars", "3"]);
+        assert_eq!(total, 2);
+        assert_eq!(output, "…aaaneedlebbb…\n…rt needle\n");
+    }
+
+    #[test]
+    fn context_chars_with_invert_shows_the_line_start() {
+        let dir = fixture_dir("context-chars-invert");
+        let path = fixture(&dir, "minified.js", "abcdefgh\nneedle\nxy\n");
+        let (output, total) = run_args(&["-q", "needle", "-p", &path, "--context-chars", "3", "-v"]);
+        assert_eq!(total, 2);
+        assert_eq!(output, "abc…\nxy\n");
+    }
+
+    #[test]
+    fn summarize_after_prints_n_lines_and_counts_the_rest() {
+        let dir = fixture_dir("summarize");
+        let path = fixture(&dir, "log.txt", "foo 1\nbar\nfoo 2\nfoo 3 foo\nfoo 4\n");
+        let (output, total) = run_args(&["-q", "foo", "-p", &path, "--summarize-after", "2"]);
+        assert_eq!(total, 4);
+        assert_eq!(output, "foo 1 \nfoo 2 \n... and 2 more matches\n");
+
+        let (output, _) = run_args(&["-q", "foo", "-p", &path, "--summarize-after", "1", "-o"]);
+        assert_eq!(output, "foo\n... and 3 more matches\n");
+        let (output, _) = run_args(&["-q", "foo", "-p", &path, "--summarize-after", "1", "--context-chars", "1"]);
+        assert_eq!(output, "foo …\n... and 3 more matches\n");
+        let (output, _) = run_args(&["-q", "foo", "-p", &path, "--summarize-after", "9"]);
+        assert!(!output.contains("more matches"));
+    }
+
+    #[test]
+    fn summarize_after_rejects_structured_output() {
+        for flag in ["--json", "--json-array", "--csv"] {
+            let err = build_error(&["-q", "foo", "--summarize-after", "1", flag]);
+            assert_eq!(err, format!("--summarize-after only works with text output, not {flag}"));
+        }
+    }
+
+    #[test]
+    fn emit_to_streams_records_to_a_listener() {
+        let dir = fixture_dir("emit-to");
+        let path = fixture(&dir, "log.txt", "foo 1\nbar\nfoo 2\n");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let receiver = thread::spawn(move || {
+            let mut received = String::new();
+            listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let (output, total) = run_args(&["-q", "foo", "-p", &path, "--emit-to", &address]);
+        assert_eq!(total, 2);
+        assert_eq!(output, "", "records go to the listener, not stdout");
+        let records: Vec<serde_json::Value> =
+            receiver.join().unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["line_number"], 3);
+        assert_eq!(records[1]["line"], "foo 2");
+    }
+
+    #[test]
+    fn emit_to_conflicts_with_other_output_modes() {
+        for flag in ["--json", "--json-array", "--csv", "-c", "-l"] {
+            let err = build_error(&["-q", "foo", "--emit-to", "127.0.0.1:1", flag]);
+            assert!(err.contains("--emit-to") && err.ends_with("conflict, pass only one of them"), "{err}");
+        }
+    }
+
+    #[test]
+    fn paragraph_prints_whole_blocks() {
+        let dir = fixture_dir("paragraph");
+        let path = fixture(&dir, "notes.txt", "one foo\ntwo\n\nthree\nfour\n\nfive foo\n");
+        let (output, total) = run_args(&["-q", "foo", "-p", &path, "--paragraph"]);
+        assert_eq!(total, 2);
+        assert_eq!(output, "1:one foo \ntwo\n\n7:five foo \n");
+    }
+
+    #[test]
+    fn paragraph_conflicts_with_other_output_modes() {
+        for flag in ["-c", "-l", "-L", "--json", "--json-array", "--csv"] {
+            let err = build_error(&["-q", "foo", "--paragraph", flag]);
+            assert!(err.starts_with("--paragraph and ") && err.ends_with(" conflict, pass only one of them"), "{err}");
+        }
+        let line_options: &[&[&str]] = &[
+            &["-m", "1"],
+            &["--summarize-after", "1"],
+            &["--in-comments"],
+            &["--in-strings"],
+            &["-o"],
+            &["--context-chars", "5"],
+            &["-A", "1"],
+            &["-B", "1"],
+            &["-C", "1"],
+        ];
+        for option in line_options {
+            let err = build_error(&[&["-q", "foo", "--paragraph"], *option].concat());
+            assert!(err.starts_with("--paragraph and ") && err.ends_with(" conflict, pass only one of them"), "{err}");
+        }
+    }
+}
@@ -1,69 +1,234 @@
+use std::collections::HashSet;
 use std::env;
 use std::fs;
-use std::io::{self, Result as IoResult};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crossterm::{
-    event::{self, Event, KeyCode, MouseEventKind},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use ignore::WalkBuilder;
+use minigrep::regions::{self, Region};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Rect},
+    layout::{Constraint, Direction, Layout, Position, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Frame, Terminal,
     backend::CrosstermBackend,
 };
 
+// Every key the browser handles, shown by the ? overlay. Add new bindings here as well.
+const KEYBINDINGS: &[(&str, &str)] = &[
+    ("j / Down", "Select the next entry"),
+    ("k / Up", "Select the previous entry"),
+    ("gg / G", "Select the first or last entry"),
+    ("Ctrl-d / Ctrl-u", "Move the selection down or up by half a page"),
+    ("l / Right", "Expand the selected directory"),
+    ("h / Left", "Collapse the selected directory"),
+    ("Space", "Expand or collapse the selected directory"),
+    ("- / Backspace", "Browse the parent of the current directory"),
+    ("r", "Browse the selected directory"),
+    ("PageDown / PageUp", "Scroll the file contents by a page"),
+    ("Home / End", "Jump to the top or bottom of the file"),
+    ("/", "Find a file by name, Enter jumps to it"),
+    ("Tab / Shift-Tab", "Move focus between the panels, j/k scroll the focused preview"),
+    ("Enter", "Open the search panel, which searches as you type"),
+    ("Esc", "Close the search panel or this help"),
+    ("n / N", "Jump to the next or previous search match"),
+    (".", "Show or hide hidden files"),
+    ("i", "Show or hide files ignored by .gitignore"),
+    ("p", "Hide or show empty directories"),
+    ("y", "Copy the selected path to the clipboard"),
+    ("Y", "Copy the lines matching the search to the clipboard"),
+    ("x", "Toggle the hex view"),
+    ("w", "Wrap long lines in the file contents"),
+    ("#", "Toggle line numbers"),
+    ("c", "Toggle tree depth colors"),
+    ("> / < or Ctrl-Right / Ctrl-Left", "Grow or shrink the focused panel"),
+    ("v", "Stack the panels or put them side by side"),
+    ("?", "Show or hide this help"),
+    ("q", "Quit"),
+];
+
+const FINDER_MAX_PATHS: usize = 100_000; // The finder stops listing paths after this many
+const FINDER_BATCH: usize = 1000; // Paths the finder's walk sends at a time
+
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(150); // Typing pause before the query is searched
+const STATUS_MESSAGE_TIME: Duration = Duration::from_secs(3);
+const SPLIT_STEP: u16 = 5; // Percent a panel grows or shrinks by per < or >
+const MIN_PANEL_PERCENT: u16 = 10;
+
+const MOUSE_SCROLL_LINES: u16 = 3; // Lines the file contents move per wheel step
+
+// Cycled through by depth when tree depth colors are on
+const DEPTH_PALETTE: [Color; 5] = [Color::Blue, Color::Green, Color::Yellow, Color::Magenta, Color::Cyan];
+
+// Nerd Font glyph shown before a tree node, picked by directory status and extension
+fn node_icon(node: &TreeNode) -> &'static str {
+    if node.is_dir {
+        return "\u{f07b}";
+    }
+    match node.path.extension().and_then(|ext| ext.to_str()).unwrap_or_default() {
+        "rs" => "\u{e7a8}",
+        "toml" => "\u{e615}",
+        "md" => "\u{f48a}",
+        "json" => "\u{e60b}",
+        "py" => "\u{e606}",
+        "js" | "ts" => "\u{e74e}",
+        "txt" => "\u{f15c}",
+        "lock" => "\u{f023}",
+        _ => "\u{f15b}",
+    }
+}
+
 #[derive(Clone, Debug)]
 struct TreeNode {
     path: PathBuf,
     is_dir: bool,
     depth: usize,
+    error: Option<String>, // Why the entry (or, for an expanded directory, its listing) couldn't be read
+}
+
+// The panel keys go to. The search panel replaces the preview while it's open, so the
+// preview can only have focus without it and the search field only with it.
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Tree,
+    Preview,
+    Search,
 }
 
 struct App {
+    root: PathBuf, // The directory the tree is built from
     tree_nodes: Vec<TreeNode>,
     selected_index: usize,
     show_hidden: bool,
+    gitignore: bool, // Leave out what .gitignore rules match, on by default
     file_contents: Option<String>,
+    highlighted: Option<Vec<Line<'static>>>, // Syntax-colored file_contents, None for unknown languages
     scroll_offset: u16, // For vertical scrolling of file contents
     show_third_panel: bool,
     search_input: String,
+    search_results: Option<Vec<minigrep::Match>>, // None until a search is run
+    search_typed_at: Option<Instant>, // When the query last changed without being searched yet
+    match_lines: Vec<usize>, // 1-based lines of search_results, highlighted in the preview
+    current_match: Option<usize>, // Index into match_lines last jumped to with n/N
+    hex_view: bool, // Show the selected file as a hexdump instead of text
+    hex_contents: Option<String>,
+    depth_colors: bool, // Color tree rows by their depth
+    icons: bool, // Nerd Font file-type icons, enabled with --icons
+    split_direction: Direction, // Panels side by side (horizontal) or stacked (vertical)
+    contents_height: u16, // Visible rows in the file contents panel, updated every frame
+    contents_width: u16,
+    wrap: bool, // Wrap long lines in the file contents panel instead of cutting them off
+    line_numbers: bool, // Show a line-number gutter in the file contents panel
+    prune_empty_dirs: bool, // Hide directories with no visible files underneath
+    watcher: Option<RecommendedWatcher>, // None when file watching isn't available
+    watched_path: Option<PathBuf>,
+    watch_events: Receiver<notify::Result<notify::Event>>,
+    preview_path: Option<PathBuf>, // The file shown (or being loaded) in the contents panel
+    preview_generation: u64, // Bumped on every read so that stale background loads are dropped
+    loading: bool, // A background load for preview_generation hasn't arrived yet
+    follow_end: bool, // Scroll to the end once the reloaded contents are shown
+    preview_sender: Sender<(u64, Preview)>,
+    previews: Receiver<(u64, Preview)>,
+    expanded: HashSet<PathBuf>, // Directories whose children are shown, all collapsed at startup
+    tree_state: ListState, // Keeps the tree scrolled so the selected node stays visible
+    tree_area: Rect, // Where the tree and contents panels were drawn last frame, for the mouse
+    contents_area: Rect,
+    show_help: bool, // The keybinding overlay toggled with ?
+    focus: Focus,
+    clipboard: Option<arboard::Clipboard>, // Opened on the first copy, kept so X11 keeps serving it
+    status_message: Option<(String, Instant)>, // Shown in the status bar for a few seconds
+    two_panel_split: [u16; 2], // Panel sizes in percent, adjusted with < and >
+    three_panel_split: [u16; 3],
+    pending_g: bool, // The last key was a g, so another one jumps to the top
+    show_finder: bool, // The fuzzy file finder opened with /
+    finder_query: String,
+    finder_paths: Vec<PathBuf>, // Everything under root, listed on a worker thread when the finder opens
+    finder_walk: Option<Receiver<Vec<PathBuf>>>, // More finder_paths on the way, None once the walk ended
+    finder_ranked: Vec<usize>, // Indexes into finder_paths matching finder_query, best first
+    finder_selected: usize, // Index into finder_ranked
 }
 
 impl App {
-    fn new() -> Self {
-        let start_path = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    fn new(root: PathBuf) -> Self {
+        let (watch_sender, watch_events) = mpsc::channel();
+        let (preview_sender, previews) = mpsc::channel();
         Self {
-            tree_nodes: Self::build_tree(&start_path, 0, false),
+            tree_nodes: Self::build_tree(&root, 0, false, true, &HashSet::new()).unwrap_or_default(),
+            root,
             selected_index: 0,
             show_hidden: false,
+            gitignore: true,
             file_contents: None,
+            highlighted: None,
             scroll_offset: 0,
             show_third_panel: false,
             search_input: String::new(),
+            search_results: None,
+            search_typed_at: None,
+            match_lines: Vec::new(),
+            current_match: None,
+            hex_view: false,
+            hex_contents: None,
+            depth_colors: false,
+            icons: false,
+            split_direction: Direction::Horizontal,
+            contents_height: 0,
+            contents_width: 0,
+            wrap: false,
+            line_numbers: false,
+            prune_empty_dirs: false,
+            watcher: notify::recommended_watcher(watch_sender).ok(),
+            watched_path: None,
+            watch_events,
+            preview_path: None,
+            preview_generation: 0,
+            loading: false,
+            follow_end: false,
+            preview_sender,
+            previews,
+            expanded: HashSet::new(),
+            tree_state: ListState::default(),
+            tree_area: Rect::default(),
+            contents_area: Rect::default(),
+            show_help: false,
+            focus: Focus::Tree,
+            clipboard: None,
+            status_message: None,
+            two_panel_split: [40, 60],
+            three_panel_split: [33, 33, 34],
+            pending_g: false,
+            show_finder: false,
+            finder_query: String::new(),
+            finder_paths: Vec::new(),
+            finder_walk: None,
+            finder_ranked: Vec::new(),
+            finder_selected: 0,
         }
     }
 
-    fn build_tree(path: &Path, depth: usize, show_hidden: bool) -> Vec<TreeNode> {
+    // Lists the entries of path, descending only into expanded directories so that a big
+    // tree doesn't have to be walked up front
+    fn build_tree(path: &Path, depth: usize, show_hidden: bool, gitignore: bool, expanded: &HashSet<PathBuf>) -> io::Result<Vec<TreeNode>> {
         let mut nodes = Vec::new();
 
-        // Read directory entries
-        let entries = match fs::read_dir(path) {
-            Ok(entries) => entries,
-            Err(_) => return nodes,
-        };
-
         // Convert and filter entries
-        let mut sorted_entries: Vec<_> = entries
-            .filter_map(|entry| entry.ok())
+        let mut sorted_entries: Vec<_> = Self::read_entries(path, gitignore)?
+            .into_iter()
             .filter(|entry| {
                 if !show_hidden {
                     !entry.file_name()
-                        .to_str()
+                        .and_then(|name| name.to_str())
                         .map(|name| name.starts_with('.'))
                         .unwrap_or(false)
                 } else {
@@ -72,107 +237,967 @@ impl App {
             })
             .collect();
 
-        sorted_entries.sort_by_key(|a| a.file_name());
+        sorted_entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
 
         // Convert to tree nodes
-        for entry in sorted_entries {
-            let entry_path = entry.path();
-            let is_dir = entry_path.is_dir();
-            
-            nodes.push(TreeNode {
+        for entry_path in sorted_entries {
+            let metadata = fs::metadata(&entry_path);
+            let is_dir = metadata.as_ref().is_ok_and(|metadata| metadata.is_dir());
+            let mut node = TreeNode {
                 path: entry_path.clone(),
                 is_dir,
                 depth,
-            });
+                error: metadata.err().map(|err| describe_error(&err)),
+            };
 
-            // Recursively add subdirectories
-            if is_dir {
-                nodes.extend(Self::build_tree(&entry_path, depth + 1, show_hidden));
+            // Recursively add expanded subdirectories, marking the ones we can't list
+            // so they don't just look empty
+            let mut children = Vec::new();
+            if is_dir && expanded.contains(&entry_path) {
+                match Self::build_tree(&entry_path, depth + 1, show_hidden, gitignore, expanded) {
+                    Ok(nodes) => children = nodes,
+                    Err(err) => node.error = Some(describe_error(&err)),
+                }
             }
+            nodes.push(node);
+            nodes.extend(children);
         }
 
-        nodes
+        Ok(nodes)
     }
 
+    // The direct children of a directory, leaving out what .gitignore (and .git/info/exclude and the
+    // global excludes file) rules out when gitignore is set. Hidden files are filtered by the caller.
+    fn read_entries(path: &Path, gitignore: bool) -> io::Result<Vec<PathBuf>> {
+        // The walk below only reports errors as entries, so check the directory can be listed first
+        let entries = fs::read_dir(path)?;
+        if !gitignore {
+            return Ok(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect());
+        }
+
+        let entries = WalkBuilder::new(path)
+            .max_depth(Some(1))
+            .standard_filters(false)
+            .parents(true) // Rules from the .gitignore files above path apply too
+            .git_ignore(true)
+            .git_exclude(true)
+            .git_global(true)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.depth() == 1) // Depth 0 is the directory itself
+            .map(|entry| entry.into_path())
+            .collect();
+        Ok(entries)
+    }
+
+    // Reads the selected file into the contents panel. Small files are read right away, bigger
+    // ones on a worker thread while the panel says "Loading…", see receive_preview.
     fn read_file_contents(&mut self) {
-        if let Some(node) = self.tree_nodes.get(self.selected_index) {
-            if !node.is_dir {
-                match fs::read_to_string(&node.path) {
-                    Ok(contents) => self.file_contents = Some(contents),
-                    Err(_) => self.file_contents = Some("Unable to read file contents".to_string()),
+        let Some(node) = self.tree_nodes.get(self.selected_index) else { return };
+        let path = node.path.clone();
+        let reloading = self.preview_path.as_ref() == Some(&path);
+        self.preview_generation += 1; // Anything still loading is for an older selection now
+        self.loading = false;
+
+        if node.is_dir {
+            self.preview_path = None;
+            self.show_preview(None);
+        } else if fs::metadata(&path).is_ok_and(|metadata| metadata.len() <= ASYNC_PREVIEW_BYTES) {
+            self.preview_path = Some(path.clone());
+            self.show_preview(Some(load_preview(&path, self.hex_view)));
+        } else {
+            // A reload keeps showing the old contents until the new ones arrive
+            if !reloading {
+                self.file_contents = Some(LOADING.to_string());
+                self.highlighted = None;
+                if self.search_results.is_some() {
+                    self.run_search(); // Don't leave the last file's matches up meanwhile
                 }
-            } else {
-                self.file_contents = None;
             }
+            if !self.hex_view {
+                self.hex_contents = None;
+            } else if !reloading || self.hex_contents.is_none() {
+                self.hex_contents = Some(LOADING.to_string());
+            }
+            self.scroll_offset = self.scroll_offset.min(self.max_scroll());
+
+            self.preview_path = Some(path.clone());
+            self.loading = true;
+            let (generation, hex_view, sender) = (self.preview_generation, self.hex_view, self.preview_sender.clone());
+            thread::spawn(move || {
+                let _ = sender.send((generation, load_preview(&path, hex_view)));
+            });
+        }
+        self.watch_selected();
+    }
+
+    // Shows a preview that finished loading, unless the selection has moved on since
+    fn receive_preview(&mut self) {
+        while let Ok((generation, preview)) = self.previews.try_recv() {
+            if generation == self.preview_generation {
+                self.loading = false;
+                self.show_preview(Some(preview));
+            }
+        }
+    }
+
+    // Puts a preview (None for a directory) in the contents panel
+    fn show_preview(&mut self, preview: Option<Preview>) {
+        (self.file_contents, self.highlighted, self.hex_contents) = match preview {
+            Some(preview) => (Some(preview.contents), preview.highlighted, preview.hex),
+            None => (None, None, None),
+        };
+        // Keep the matches in step with what is shown, whether another file or a reload
+        if self.search_results.is_some() {
+            self.run_search();
+        }
+        // A shorter file than the last one mustn't leave the panel scrolled past its end
+        self.scroll_offset = self.scroll_offset.min(self.max_scroll());
+        if std::mem::take(&mut self.follow_end) {
+            self.scroll_offset = self.max_scroll();
+        }
+    }
+
+    // Watches the selected file for changes, dropping the watch on the previous one
+    fn watch_selected(&mut self) {
+        let selected = self.tree_nodes.get(self.selected_index).filter(|node| !node.is_dir);
+        if selected.map(|node| &node.path) == self.watched_path.as_ref() {
+            return;
+        }
+        let Some(watcher) = self.watcher.as_mut() else { return };
+
+        if let Some(old_path) = self.watched_path.take() {
+            let _ = watcher.unwatch(&old_path);
+        }
+        if let Some(node) = selected {
+            if watcher.watch(&node.path, RecursiveMode::NonRecursive).is_ok() {
+                self.watched_path = Some(node.path.clone());
+            }
+        }
+    }
+
+    // Re-reads the watched file after it changes on disk, following the end if we were at the bottom
+    fn reload_if_changed(&mut self) {
+        let mut changed = false;
+        while let Ok(event) = self.watch_events.try_recv() {
+            if let Ok(event) = event {
+                changed |= matches!(event.kind, notify::EventKind::Modify(_) | notify::EventKind::Create(_));
+            }
+        }
+        if !changed {
+            return;
         }
+
+        self.follow_end = self.scroll_offset >= self.max_scroll();
+        self.read_file_contents();
+    }
+
+    // Searches the selected file's contents for the typed query, using the library's matcher
+    fn run_search(&mut self) {
+        self.search_results = match &self.file_contents {
+            Some(contents) if !self.search_input.is_empty() => {
+                Some(minigrep::search(&self.search_input, contents, false))
+            }
+            _ => None,
+        };
+        self.match_lines = self.search_results.iter().flatten().map(|result| result.line_number).collect();
+        self.current_match = None;
+    }
+
+    // Searches for the typed query once typing has paused for SEARCH_DEBOUNCE
+    fn run_pending_search(&mut self) {
+        if self.search_typed_at.is_some_and(|typed_at| typed_at.elapsed() >= SEARCH_DEBOUNCE) {
+            self.search_typed_at = None;
+            self.run_search();
+        }
+    }
+
+    // The largest scroll_offset that still fills the contents panel
+    fn max_scroll(&self) -> u16 {
+        let shown = if self.hex_view { &self.hex_contents } else { &self.file_contents };
+        let line_count = shown.as_ref().map_or(0, |contents| contents.lines().count());
+        let Some(contents) = shown.as_ref().filter(|_| self.wrap) else {
+            return line_count.saturating_sub(self.contents_height as usize).min(u16::MAX as usize) as u16;
+        };
+
+        // Wrapped lines take several rows, so count back from the end until the panel is full.
+        // Word wrapping can break a little earlier than this, costing at most a blank row or two.
+        let gutter = if self.line_numbers && !self.hex_view { line_count.max(1).to_string().len() + 1 } else { 0 };
+        let width = (self.contents_width as usize).saturating_sub(gutter).max(1);
+        let mut rows = 0;
+        let mut offset = line_count;
+        for line in contents.lines().rev() {
+            rows += line.chars().count().div_ceil(width).max(1);
+            if rows > self.contents_height as usize {
+                break;
+            }
+            offset -= 1;
+        }
+        offset.min(u16::MAX as usize) as u16
+    }
+
+    // Scrolls to the next (or with backwards, the previous) search match, wrapping around
+    fn jump_to_match(&mut self, backwards: bool) {
+        let count = self.match_lines.len();
+        if count == 0 || self.hex_view {
+            return;
+        }
+        let next = match (self.current_match, backwards) {
+            (None, false) => 0,
+            (None, true) => count - 1,
+            (Some(current), false) => (current + 1) % count,
+            (Some(current), true) => (current + count - 1) % count,
+        };
+        self.current_match = Some(next);
+        let line = self.match_lines[next].saturating_sub(1);
+        self.scroll_offset = (line.min(u16::MAX as usize) as u16).min(self.max_scroll());
+    }
+
+    // Clicking a tree row selects it, and the wheel scrolls the file contents under the pointer
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        let position = Position::new(mouse.column, mouse.row);
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) if self.tree_area.contains(position) => {
+                // Rows start below the top border and are shifted by the list's scroll
+                let row = mouse.row.saturating_sub(self.tree_area.y + 1) as usize;
+                let index = self.tree_state.offset() + row;
+                if mouse.row > self.tree_area.y && index < self.tree_nodes.len() {
+                    self.focus = Focus::Tree;
+                    self.selected_index = index;
+                    self.read_file_contents();
+                    self.scroll_offset = 0;
+                }
+            }
+            MouseEventKind::ScrollDown if !self.show_third_panel && self.contents_area.contains(position) => {
+                self.scroll_offset = self.scroll_offset.saturating_add(MOUSE_SCROLL_LINES).min(self.max_scroll());
+            }
+            MouseEventKind::ScrollUp if !self.show_third_panel && self.contents_area.contains(position) => {
+                self.scroll_offset = self.scroll_offset.saturating_sub(MOUSE_SCROLL_LINES);
+            }
+            _ => {}
+        }
+    }
+
+    // Selects the node at index, clamped to the tree, and previews it from the top
+    fn select_node(&mut self, index: usize) {
+        let index = index.min(self.tree_nodes.len().saturating_sub(1));
+        if index != self.selected_index {
+            self.selected_index = index;
+            self.read_file_contents();
+            self.scroll_offset = 0;
+        }
+    }
+
+    // Half the visible tree rows, for Ctrl-d and Ctrl-u
+    fn half_page(&self) -> usize {
+        (self.tree_area.height.saturating_sub(2) as usize / 2).max(1) // Minus the borders
+    }
+
+    // Opens the finder on every path under root, including collapsed directories, filtered by
+    // the same hidden and .gitignore settings as the tree. The paths are listed on a worker
+    // thread, at most FINDER_MAX_PATHS of them, so a huge tree doesn't hold up the browser.
+    fn open_finder(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        let walk = WalkBuilder::new(&self.root)
+            .standard_filters(false)
+            .hidden(!self.show_hidden)
+            .git_ignore(self.gitignore)
+            .git_exclude(self.gitignore)
+            .git_global(self.gitignore)
+            .parents(self.gitignore)
+            .build();
+        thread::spawn(move || {
+            let paths = walk
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.depth() > 0)
+                .map(|entry| entry.into_path())
+                .take(FINDER_MAX_PATHS);
+            let mut batch = Vec::with_capacity(FINDER_BATCH);
+            for path in paths {
+                batch.push(path);
+                // The receiver is gone once the finder closed, which ends the walk early
+                if batch.len() == FINDER_BATCH && sender.send(std::mem::take(&mut batch)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(batch);
+        });
+
+        self.finder_paths.clear();
+        self.finder_walk = Some(receiver);
+        self.finder_query.clear();
+        self.rank_finder();
+        self.show_finder = true;
+    }
+
+    fn close_finder(&mut self) {
+        self.show_finder = false;
+        self.finder_walk = None;
+    }
+
+    // Adds the paths the finder's walk listed since the last frame
+    fn receive_finder_paths(&mut self) {
+        let Some(walk) = &self.finder_walk else { return };
+        let mut received = false;
+        loop {
+            match walk.try_recv() {
+                Ok(batch) => {
+                    self.finder_paths.extend(batch);
+                    received = true;
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.finder_walk = None;
+                    break;
+                }
+            }
+        }
+        if received {
+            // Keep the selected result selected as new paths are ranked in around it
+            let selected = self.finder_ranked.get(self.finder_selected).copied();
+            self.rank_finder();
+            self.finder_selected = selected
+                .and_then(|selected| self.finder_ranked.iter().position(|&index| index == selected))
+                .unwrap_or(0);
+        }
+    }
+
+    // Ranks the finder paths against finder_query, once per change rather than every frame
+    fn rank_finder(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .finder_paths
+            .iter()
+            .enumerate()
+            .filter_map(|(index, path)| {
+                let relative = path.strip_prefix(&self.root).unwrap_or(path);
+                fuzzy_score(&self.finder_query, &relative.to_string_lossy()).map(|score| (score, index))
+            })
+            .collect();
+        // Shorter paths win ties, the sort is stable so equal ones keep the walk order
+        scored.sort_by_key(|&(score, index)| (std::cmp::Reverse(score), self.finder_paths[index].as_os_str().len()));
+        self.finder_ranked = scored.into_iter().map(|(_, index)| index).collect();
+        self.finder_selected = 0;
+    }
+
+    // Expands the directories leading to the chosen finder result and selects it
+    fn open_finder_result(&mut self) {
+        let Some(&index) = self.finder_ranked.get(self.finder_selected) else { return };
+        let path = self.finder_paths[index].clone();
+        for dir in path.ancestors().skip(1).take_while(|dir| *dir != self.root) {
+            self.expanded.insert(dir.to_path_buf());
+        }
+        self.close_finder();
+        self.rebuild_tree();
+        if let Some(index) = self.tree_nodes.iter().position(|node| node.path == path) {
+            self.select_node(index);
+        }
+    }
+
+    // Moves focus to the next (or with backwards, the previous) panel on screen
+    fn cycle_focus(&mut self, backwards: bool) {
+        let panels = if self.show_third_panel { [Focus::Tree, Focus::Search] } else { [Focus::Tree, Focus::Preview] };
+        let current = panels.iter().position(|&panel| panel == self.focus).unwrap_or(0);
+        let step = if backwards { panels.len() - 1 } else { 1 };
+        self.focus = panels[(current + step) % panels.len()];
+    }
+
+    // Grows (or shrinks) the focused panel by SPLIT_STEP percent, moving its boundary with the
+    // next panel, or the previous one for the last panel. No panel gets below MIN_PANEL_PERCENT.
+    fn resize_focused(&mut self, grow: bool) {
+        let split: &mut [u16] = if self.show_third_panel { &mut self.three_panel_split } else { &mut self.two_panel_split };
+        let panel = match self.focus {
+            Focus::Tree => 0,
+            Focus::Preview | Focus::Search => 1,
+        };
+        let neighbor = if panel + 1 < split.len() { panel + 1 } else { panel - 1 };
+        let (from, to) = if grow { (neighbor, panel) } else { (panel, neighbor) };
+        let step = SPLIT_STEP.min(split[from].saturating_sub(MIN_PANEL_PERCENT));
+        split[from] -= step;
+        split[to] += step;
+    }
+
+    // Copies the selected node's absolute path to the clipboard, reporting how it went in the status bar
+    fn copy_selected_path(&mut self) {
+        let Some(node) = self.tree_nodes.get(self.selected_index) else { return };
+        let path = std::path::absolute(&node.path).unwrap_or_else(|_| node.path.clone());
+        let path = path.to_string_lossy().into_owned();
+
+        let message = match self.copy_to_clipboard(&path) {
+            Ok(()) => format!("Copied: {}", path),
+            Err(err) => format!("Couldn't copy the path: {}", err),
+        };
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    // Copies the selected file's matching lines to the clipboard, one per line
+    fn copy_matching_lines(&mut self) {
+        let message = match self.matching_text() {
+            None => "No search matches to copy".to_string(),
+            Some(text) => match self.copy_to_clipboard(&text) {
+                Ok(()) => format!("Copied {} matching lines", self.match_lines.len()),
+                Err(err) => format!("Couldn't copy the matching lines: {}", err),
+            },
+        };
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    // The lines of match_lines joined by newlines, None without matches
+    fn matching_text(&self) -> Option<String> {
+        let contents = self.file_contents.as_ref().filter(|_| !self.match_lines.is_empty())?;
+        let lines: Vec<&str> = contents.lines().collect();
+        let matching: Vec<&str> = self.match_lines.iter().filter_map(|&n| lines.get(n.wrapping_sub(1)).copied()).collect();
+        Some(matching.join("\n"))
+    }
+
+    // The clipboard is opened on first use, it isn't there without a display server
+    fn copy_to_clipboard(&mut self, text: &str) -> Result<(), String> {
+        if self.clipboard.is_none() {
+            self.clipboard = arboard::Clipboard::new().ok();
+        }
+        match self.clipboard.as_mut().map(|clipboard| clipboard.set_text(text)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(err)) => Err(err.to_string()),
+            None => Err("no clipboard available".to_string()),
+        }
+    }
+
+    fn toggle_split_direction(&mut self) {
+        self.split_direction = match self.split_direction {
+            Direction::Horizontal => Direction::Vertical,
+            Direction::Vertical => Direction::Horizontal,
+        };
+    }
+
+    fn toggle_hex_view(&mut self) {
+        self.hex_view = !self.hex_view;
+        self.scroll_offset = 0;
+        self.read_file_contents();
+    }
+
+    // Drops directory nodes that have no file anywhere below them. Nodes are in depth-first
+    // order, so a directory's descendants are exactly the following nodes with a greater depth.
+    // Collapsed directories haven't been read, so they are always kept.
+    fn prune_empty_dirs(nodes: Vec<TreeNode>, expanded: &HashSet<PathBuf>) -> Vec<TreeNode> {
+        let mut keep = vec![true; nodes.len()];
+        let mut open_dirs: Vec<(usize, bool)> = Vec::new(); // (node index, has a file below)
+
+        for (index, node) in nodes.iter().enumerate() {
+            while open_dirs.last().is_some_and(|&(dir, _)| nodes[dir].depth >= node.depth) {
+                Self::close_dir(&mut open_dirs, &mut keep);
+            }
+            if node.is_dir {
+                // Collapsed and unreadable directories are kept, we don't know what's inside
+                open_dirs.push((index, !expanded.contains(&node.path) || node.error.is_some()));
+            } else if let Some(parent) = open_dirs.last_mut() {
+                parent.1 = true;
+            }
+        }
+        while !open_dirs.is_empty() {
+            Self::close_dir(&mut open_dirs, &mut keep);
+        }
+
+        nodes
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(node, keep)| keep.then_some(node))
+            .collect()
+    }
+
+    fn close_dir(open_dirs: &mut Vec<(usize, bool)>, keep: &mut [bool]) {
+        if let Some((dir, has_file)) = open_dirs.pop() {
+            keep[dir] = has_file;
+            if let Some(parent) = open_dirs.last_mut() {
+                parent.1 |= has_file;
+            }
+        }
+    }
+
+    fn visible_nodes(&self) -> Vec<TreeNode> {
+        let nodes = Self::build_tree(&self.root, 0, self.show_hidden, self.gitignore, &self.expanded).unwrap_or_default();
+        if self.prune_empty_dirs { Self::prune_empty_dirs(nodes, &self.expanded) } else { nodes }
+    }
+
+    // Rebuilds the tree keeping the selected path selected, or failing that the same row.
+    // The expanded set lives outside the tree, so it survives as well.
+    fn rebuild_tree(&mut self) {
+        let selected = self.tree_nodes.get(self.selected_index).map(|node| node.path.clone());
+        self.tree_nodes = self.visible_nodes();
+        self.selected_index = selected
+            .and_then(|path| self.tree_nodes.iter().position(|node| node.path == path))
+            .unwrap_or_else(|| self.selected_index.min(self.tree_nodes.len().saturating_sub(1)));
+    }
+
+    // Expands or collapses the selected directory, or flips it with None. Only its descendants
+    // change, so the selection stays where it is. Expansion is remembered per path, so
+    // re-expanding a directory brings back its expanded subdirectories too.
+    fn set_expanded(&mut self, expand: Option<bool>) {
+        let Some(node) = self.tree_nodes.get(self.selected_index).filter(|node| node.is_dir) else { return };
+        let expand = expand.unwrap_or(!self.expanded.contains(&node.path));
+        if expand {
+            self.expanded.insert(node.path.clone());
+        } else {
+            self.expanded.remove(&node.path);
+        }
+        self.tree_nodes = self.visible_nodes();
+        self.selected_index = self.selected_index.min(self.tree_nodes.len().saturating_sub(1));
+    }
+
+    // Roots the tree at the parent of the current root, with the old root expanded and selected
+    fn go_to_parent(&mut self) {
+        let Some(parent) = self.root.parent().map(Path::to_path_buf) else { return }; // Already at the filesystem root
+        let old_root = std::mem::replace(&mut self.root, parent);
+        self.expanded.insert(old_root.clone());
+        self.show_new_root(Some(old_root));
+    }
+
+    // Roots the tree at the selected directory, like cd
+    fn enter_selected_dir(&mut self) {
+        let Some(node) = self.tree_nodes.get(self.selected_index).filter(|node| node.is_dir && node.error.is_none()) else {
+            return;
+        };
+        self.root = node.path.clone();
+        self.show_new_root(None);
+    }
+
+    // Rebuilds the tree after root changed, selecting select or else the first node
+    fn show_new_root(&mut self, select: Option<PathBuf>) {
+        self.tree_nodes = self.visible_nodes();
+        self.selected_index = select
+            .and_then(|path| self.tree_nodes.iter().position(|node| node.path == path))
+            .unwrap_or(0);
+        self.preview_path = None;
+        self.show_preview(None); // For an empty directory, where there's nothing to read
+        self.read_file_contents();
+        self.scroll_offset = 0;
     }
 
     fn toggle_hidden(&mut self) {
         self.show_hidden = !self.show_hidden;
-        let start_path = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-        self.tree_nodes = Self::build_tree(&start_path, 0, self.show_hidden);
-        self.selected_index = 0;
+        self.rebuild_tree();
+    }
+
+    fn toggle_gitignore(&mut self) {
+        self.gitignore = !self.gitignore;
+        self.rebuild_tree();
+    }
+
+    fn toggle_prune_empty_dirs(&mut self) {
+        self.prune_empty_dirs = !self.prune_empty_dirs;
+        self.rebuild_tree();
+    }
+}
+
+// A short reason for the tree, e.g. "permission denied"
+fn describe_error(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => "broken link".to_string(), // Listed by the directory but gone when followed
+        kind => kind.to_string(),
+    }
+}
+
+const ASYNC_PREVIEW_BYTES: u64 = 64 * 1024; // Bigger files are read on a worker thread
+const LOADING: &str = "Loading…";
+
+// A file read for the contents panel
+struct Preview {
+    contents: String,
+    highlighted: Option<Vec<Line<'static>>>, // Syntax-colored contents, None for unknown languages
+    hex: Option<String>, // Only made in the hex view
+}
+
+// Reads and prepares a file for the contents panel, cheap to show once it's done
+fn load_preview(path: &Path, hex_view: bool) -> Preview {
+    let preview = read_preview(path);
+    let mut highlighted = None;
+    let contents = match &preview {
+        Ok((bytes, size)) => match preview_text(bytes, *size) {
+            Some(contents) => {
+                // Highlighted once here so that redraws while scrolling stay cheap
+                highlighted = highlight_source(path, &contents);
+                contents
+            }
+            None => "Unable to read file contents".to_string(),
+        },
+        Err(_) => "Unable to read file contents".to_string(),
+    };
+    // Hex rows come from the raw bytes, so they work for binary files too
+    let hex = hex_view.then(|| match &preview {
+        Ok((bytes, _)) => hex_dump(bytes),
+        Err(_) => "Unable to read file contents".to_string(),
+    });
+    Preview { contents, highlighted, hex }
+}
+
+const PREVIEW_MAX_BYTES: u64 = 1024 * 1024; // Only this much of a file is read for the preview
+
+// The first PREVIEW_MAX_BYTES of a file along with its full size
+fn read_preview(path: &Path) -> io::Result<(Vec<u8>, u64)> {
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut bytes = Vec::new();
+    file.take(PREVIEW_MAX_BYTES).read_to_end(&mut bytes)?;
+    Ok((bytes, size))
+}
+
+// The text shown for a file's preview bytes: a placeholder for binary files (a NUL early on),
+// and a note at the end when the file was cut short. None when it isn't valid UTF-8.
+fn preview_text(bytes: &[u8], size: u64) -> Option<String> {
+    if minigrep::looks_binary(bytes) {
+        return Some(format!("<binary file, {} bytes>", size));
+    }
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // The cut can land inside a character, which isn't an error in the file itself
+        Err(err) if err.error_len().is_none() => std::str::from_utf8(&bytes[..err.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if (bytes.len() as u64) < size {
+        Some(format!("{}\n… preview truncated, showing {} of {} bytes", text, bytes.len(), size))
+    } else {
+        Some(text.to_string())
+    }
+}
+
+// Formats bytes as rows of offset, 16 hex bytes and an ASCII gutter
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut rows = Vec::new();
+
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        rows.push(format!("{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii));
     }
+
+    rows.join("\n")
+}
+
+// Colors comments, strings and keywords, picking the language from the file extension.
+// None for languages the tokenizer doesn't know, which are shown plain.
+fn highlight_source(path: &Path, contents: &str) -> Option<Vec<Line<'static>>> {
+    let syntax = regions::syntax_for(&path.to_string_lossy())?;
+    let lines = regions::split_regions(contents, syntax)
+        .into_iter()
+        .map(|segments| {
+            let mut spans = Vec::new();
+            for (region, text) in segments {
+                match region {
+                    Region::Comment => spans.push(Span::styled(text.to_string(), Style::default().fg(Color::DarkGray))),
+                    Region::Str => spans.push(Span::styled(text.to_string(), Style::default().fg(Color::Green))),
+                    Region::Code => push_code_spans(&mut spans, text, syntax.keywords()),
+                }
+            }
+            Line::from(spans)
+        })
+        .collect();
+    Some(lines)
+}
+
+// Splits code into words and the text between them, coloring the words that are keywords
+fn push_code_spans(spans: &mut Vec<Span<'static>>, code: &str, keywords: &[&str]) {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut rest = code;
+    while let Some(first) = rest.chars().next() {
+        let in_word = is_word(first);
+        let end = rest.find(|c: char| is_word(c) != in_word).unwrap_or(rest.len());
+        let (token, tail) = rest.split_at(end);
+        let style = if in_word && keywords.contains(&token) {
+            Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD)
+        } else {
+            Style::default()
+        };
+        spans.push(Span::styled(token.to_string(), style));
+        rest = tail;
+    }
+}
+
+// Exits like grep: 0 when something matched, 1 when nothing did and 2 on errors
+fn run_cli(args: &[String]) {
+    let config = match minigrep::Config::build(args) {
+        Ok(config) => config,
+        Err(minigrep::BuildError::Help) => {
+            print!("{}", minigrep::USAGE);
+            process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("Problem parsing arguments: {err}");
+            process::exit(2);
+        }
+    };
+    let count_exit = config.count_exit();
+
+    // Only the query is compiled, the paths aren't even opened
+    if config.validate_regex() {
+        if let Err(e) = minigrep::check_pattern(&config) {
+            eprintln!("{e}");
+            process::exit(2);
+        }
+        process::exit(0);
+    }
+
+    match minigrep::run(config) {
+        Ok(outcome) => process::exit(match_exit_code(outcome, count_exit)),
+        Err(e) => {
+            eprintln!("Application error: {e}");
+            process::exit(2);
+        }
+    }
+}
+
+// The exit code of a search that ran. A file that couldn't be read makes it 2 like grep,
+// whatever else matched. With --count-exit the match count becomes the exit code, so 0
+// still means no matches, but an error then exits 2 just like 2 matches do.
+fn match_exit_code(outcome: minigrep::Outcome, count_exit: bool) -> i32 {
+    match outcome.matches {
+        _ if outcome.had_errors => 2,
+        matches if count_exit => matches.min(255) as i32,
+        0 => 1,
+        _ => 0,
+    }
+}
+
+// Whether the arguments are for a command-line search rather than the browser, which only
+// takes a directory and --icons. Any other flag goes to Config::build, so that e.g. a
+// forgotten -q is reported instead of opening the browser on the -p path.
+fn wants_cli(args: &[String]) -> bool {
+    args.iter().skip(1).any(|arg| arg.starts_with('-') && arg != "--icons")
 }
 
 fn main() -> io::Result<()> {
+    // Search from the command line when flags are given, otherwise open the browser
+    let args: Vec<String> = env::args().collect();
+    if wants_cli(&args) {
+        run_cli(&args);
+        return Ok(());
+    }
+
+    // The first positional argument picks the directory to browse, the current one by default
+    let root = match args.iter().skip(1).find(|arg| !arg.starts_with('-')) {
+        // Canonical, so that going up from it with - leads to its real parent even from "dir/.."
+        Some(dir) if Path::new(dir).is_dir() => fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir)),
+        Some(dir) => {
+            let reason = if Path::new(dir).exists() { "not a directory" } else { "no such directory" };
+            eprintln!("Cannot browse {dir}: {reason}");
+            process::exit(2);
+        }
+        None => env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+    };
+
     // Terminal initialization
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // App initialization
-    let mut app = App::new();
+    let mut app = App::new(root);
+    app.icons = args.iter().any(|arg| arg == "--icons");
 
     // Main loop
     loop {
         // Main event loop for handling UI updates
+        app.receive_preview();
+        app.receive_finder_paths();
+        app.run_pending_search();
         terminal.draw(|f| ui(f, &mut app))?;
-    
+
+        // Wait briefly for input so changes to the viewed file get picked up in between,
+        // and only very briefly while a preview is loading so it shows up as soon as it's read.
+        // A typed query is searched once SEARCH_DEBOUNCE passes without another key.
+        let mut timeout = Duration::from_millis(if app.loading { 15 } else { 250 });
+        if let Some(typed_at) = app.search_typed_at {
+            timeout = timeout.min(SEARCH_DEBOUNCE.saturating_sub(typed_at.elapsed()));
+        }
+        if !event::poll(timeout)? {
+            app.reload_if_changed();
+            continue;
+        }
+
         // Handling user input
-        if let Event::Key(key) = event::read()? {
-            if app.show_third_panel {
+        let event = event::read()?;
+        if let Event::Mouse(mouse) = event {
+            app.handle_mouse(mouse);
+            continue;
+        }
+        if let Event::Key(key) = event {
+            if app.show_help {
+                if matches!(key.code, KeyCode::Char('?') | KeyCode::Esc) {
+                    app.show_help = false;
+                }
+            } else if app.show_finder {
+                match key.code {
+                    KeyCode::Char(c) => {
+                        app.finder_query.push(c);
+                        app.rank_finder();
+                    }
+                    KeyCode::Backspace => {
+                        app.finder_query.pop();
+                        app.rank_finder();
+                    }
+                    KeyCode::Down => {
+                        let last = app.finder_ranked.len().saturating_sub(1);
+                        app.finder_selected = (app.finder_selected + 1).min(last);
+                    }
+                    KeyCode::Up => {
+                        app.finder_selected = app.finder_selected.saturating_sub(1);
+                    }
+                    KeyCode::Enter => {
+                        app.open_finder_result();
+                    }
+                    KeyCode::Esc => {
+                        app.close_finder();
+                    }
+                    _ => {}
+                }
+            } else if matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+                app.cycle_focus(key.code == KeyCode::BackTab);
+            } else if key.modifiers.contains(KeyModifiers::CONTROL) && matches!(key.code, KeyCode::Left | KeyCode::Right) {
+                // Works in the search field too, where < and > are typed
+                app.resize_focused(key.code == KeyCode::Right);
+            } else if app.focus == Focus::Search {
                 // Handle input for search field in third panel
                 match key.code {
                     KeyCode::Char(c) => {
                         app.search_input.push(c);
+                        app.search_typed_at = Some(Instant::now());
                     }
                     KeyCode::Backspace => {
                         app.search_input.pop();
+                        app.search_typed_at = Some(Instant::now());
                     }
                     KeyCode::Enter => {
-                        // Trigger search
+                        app.search_typed_at = None; // No need to wait any longer
+                        app.run_search();
                     }
                     KeyCode::Esc => {
                         app.show_third_panel = false; // Close the third panel
+                        app.focus = Focus::Tree;
+                        app.search_typed_at = None;
                     }
                     _ => {}
                 }
             } else {
-                // Handle input for tree navigation when third panel is not active
+                // Handle input for tree navigation, or for scrolling when the preview has focus
+                let pending_g = std::mem::take(&mut app.pending_g); // Any other key cancels a g
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
                 match key.code {
                     KeyCode::Char('q') => break, // This break exits the main loop
-                    KeyCode::Down | KeyCode::Char('j') => {
-                        if app.selected_index < app.tree_nodes.len() - 1 {
-                            app.selected_index += 1;
-                            app.read_file_contents();
-                            app.scroll_offset = 0;
-                        }
-                    }
-                    KeyCode::Up | KeyCode::Char('k') => {
-                        if app.selected_index > 0 {
-                            app.selected_index -= 1;
-                            app.read_file_contents();
-                            app.scroll_offset = 0;
-                        }
-                    }
-                    KeyCode::Char('h') => {
+                    KeyCode::Char('?') => {
+                        app.show_help = true;
+                    }
+                    KeyCode::Char('/') => {
+                        app.open_finder();
+                    }
+                    KeyCode::Down | KeyCode::Char('j') if app.focus == Focus::Preview => {
+                        app.scroll_offset = app.scroll_offset.saturating_add(1).min(app.max_scroll());
+                    }
+                    KeyCode::Up | KeyCode::Char('k') if app.focus == Focus::Preview => {
+                        app.scroll_offset = app.scroll_offset.saturating_sub(1);
+                    }
+                    KeyCode::Down | KeyCode::Char('j')
+                        if app.selected_index + 1 < app.tree_nodes.len() =>
+                    {
+                        app.selected_index += 1;
+                        app.read_file_contents();
+                        app.scroll_offset = 0;
+                    }
+                    KeyCode::Up | KeyCode::Char('k') if app.selected_index > 0 => {
+                        app.selected_index -= 1;
+                        app.read_file_contents();
+                        app.scroll_offset = 0;
+                    }
+                    KeyCode::Char('g') if pending_g => {
+                        app.select_node(0);
+                    }
+                    KeyCode::Char('g') => {
+                        app.pending_g = true;
+                    }
+                    KeyCode::Char('G') => {
+                        app.select_node(usize::MAX);
+                    }
+                    KeyCode::Char('d') if ctrl => {
+                        app.select_node(app.selected_index + app.half_page());
+                    }
+                    KeyCode::Char('u') if ctrl => {
+                        app.select_node(app.selected_index.saturating_sub(app.half_page()));
+                    }
+                    KeyCode::Char('>') => {
+                        app.resize_focused(true);
+                    }
+                    KeyCode::Char('<') => {
+                        app.resize_focused(false);
+                    }
+                    KeyCode::Char('-') | KeyCode::Backspace => {
+                        app.go_to_parent();
+                    }
+                    KeyCode::Char('r') => {
+                        app.enter_selected_dir();
+                    }
+                    KeyCode::Char('y') => {
+                        app.copy_selected_path();
+                    }
+                    KeyCode::Char('Y') => {
+                        app.copy_matching_lines();
+                    }
+                    KeyCode::Char('.') => {
                         app.toggle_hidden();
                     }
+                    KeyCode::Char('i') => {
+                        app.toggle_gitignore();
+                    }
+                    KeyCode::Char('w') => {
+                        app.wrap = !app.wrap;
+                        app.scroll_offset = app.scroll_offset.min(app.max_scroll());
+                    }
+                    KeyCode::Char('x') => {
+                        app.toggle_hex_view();
+                    }
+                    KeyCode::Char('c') => {
+                        app.depth_colors = !app.depth_colors;
+                    }
+                    KeyCode::Char('v') => {
+                        app.toggle_split_direction();
+                    }
+                    KeyCode::Char('#') => {
+                        app.line_numbers = !app.line_numbers;
+                    }
+                    KeyCode::Char('p') => {
+                        app.toggle_prune_empty_dirs();
+                    }
+                    KeyCode::PageDown => {
+                        app.scroll_offset = app.scroll_offset.saturating_add(app.contents_height).min(app.max_scroll());
+                    }
+                    KeyCode::PageUp => {
+                        app.scroll_offset = app.scroll_offset.saturating_sub(app.contents_height);
+                    }
+                    KeyCode::Home => {
+                        app.scroll_offset = 0;
+                    }
+                    KeyCode::End => {
+                        app.scroll_offset = app.max_scroll();
+                    }
+                    KeyCode::Char('n') => {
+                        app.jump_to_match(false);
+                    }
+                    KeyCode::Char('N') => {
+                        app.jump_to_match(true);
+                    }
+                    KeyCode::Char(' ') => {
+                        app.set_expanded(None);
+                    }
+                    KeyCode::Right | KeyCode::Char('l') => {
+                        app.set_expanded(Some(true));
+                    }
+                    KeyCode::Left | KeyCode::Char('h') => {
+                        app.set_expanded(Some(false));
+                    }
                     KeyCode::Enter => {
                         app.show_third_panel = true; // Show third panel for search
+                        app.focus = Focus::Search;
+                        app.search_results = None;
                     }
                     _ => {}
                 }
@@ -183,44 +1208,59 @@ fn main() -> io::Result<()> {
 
     // Restore terminal
     disable_raw_mode()?;
-    execute!(io::stdout(), LeaveAlternateScreen)?;
+    execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
     Ok(())
 }
 
 fn ui(f: &mut Frame, app: &mut App) {
-    // Create layout with two or three columns based on show_third_panel
+    // The status bar takes the bottom row, the panels share the rest
+    let [panels_area, status_area] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(f.area());
+    f.render_widget(Paragraph::new(status_line(app)).style(Style::default().add_modifier(Modifier::REVERSED)), status_area);
+
+    // Create layout with two or three panels based on show_third_panel
     let main_layout = if app.show_third_panel {
         Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([
-                Constraint::Percentage(33), // Tree view
-                Constraint::Percentage(33), // File contents
-                Constraint::Percentage(34), // Third panel
-            ])
-            .split(f.size())
+            .direction(app.split_direction)
+            .constraints(app.three_panel_split.map(Constraint::Percentage)) // Tree, search field, results
+            .split(panels_area)
     } else {
         Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([
-                Constraint::Percentage(40), // Tree view
-                Constraint::Percentage(60), // File contents
-            ])
-            .split(f.size())
+            .direction(app.split_direction)
+            .constraints(app.two_panel_split.map(Constraint::Percentage)) // Tree, file contents
+            .split(panels_area)
     };
 
+    app.contents_height = main_layout[1].height.saturating_sub(2); // Minus the borders
+    app.contents_width = main_layout[1].width.saturating_sub(2);
+    app.tree_area = main_layout[0];
+    app.contents_area = main_layout[1];
+    app.scroll_offset = app.scroll_offset.min(app.max_scroll()); // The panel may have grown
+
     // Render tree view (no changes)
     let tree_items: Vec<ListItem> = app.tree_nodes
         .iter()
         .enumerate()
         .map(|(index, node)| {
             let indent = " ".repeat(node.depth * 2);
-            let content = format!("{}{}", indent, node.path.file_name().unwrap_or_default().to_string_lossy());
+            let fold = match (node.is_dir, app.expanded.contains(&node.path)) {
+                (true, true) => "▾ ",
+                (true, false) => "▸ ",
+                (false, _) => "  ",
+            };
+            let icon = if app.icons { format!("{} ", node_icon(node)) } else { String::new() };
+            let name = node.path.file_name().unwrap_or_default().to_string_lossy();
+            let error = node.error.as_ref().map(|err| format!(" ({})", err)).unwrap_or_default();
+            let content = format!("{}{}{}{}{}", indent, fold, icon, name, error);
 
             let style = if index == app.selected_index {
                 Style::default()
                     .fg(Color::Black)
                     .bg(Color::White)
                     .add_modifier(Modifier::BOLD)
+            } else if node.error.is_some() {
+                Style::default().fg(Color::Red)
+            } else if app.depth_colors {
+                Style::default().fg(DEPTH_PALETTE[node.depth % DEPTH_PALETTE.len()])
             } else {
                 Style::default()
             };
@@ -229,38 +1269,69 @@ fn ui(f: &mut Frame, app: &mut App) {
         })
         .collect();
 
-    let tree_block = Block::default()
-        .title(" Directory Tree ")
-        .borders(Borders::ALL);
+    let tree_block = panel_block(" Directory Tree ", app.focus == Focus::Tree);
 
     let tree_list = List::new(tree_items)
         .block(tree_block);
 
-    f.render_widget(tree_list, main_layout[0]);
+    // The list scrolls itself to the selected node, keeping its offset between frames
+    app.tree_state.select(Some(app.selected_index));
+    f.render_stateful_widget(tree_list, main_layout[0], &mut app.tree_state);
 
     // Render file contents or search field
-    let contents_block = Block::default()
-        .title(" File Contents ")
-        .borders(Borders::ALL);
+    let contents_title = match (app.hex_view, app.wrap) {
+        (false, false) => " File Contents ",
+        (true, false) => " File Contents (hex) ",
+        (false, true) => " File Contents (wrap) ",
+        (true, true) => " File Contents (hex, wrap) ",
+    };
+    let contents_block = panel_block(contents_title, app.focus == Focus::Preview);
 
     if app.show_third_panel {
         // Render search input
         let search_paragraph = Paragraph::new(app.search_input.clone())
-            .block(Block::default().title(" Search ").borders(Borders::ALL))
+            .block(panel_block(" Search ", app.focus == Focus::Search))
             .style(Style::default().fg(Color::Yellow));
         f.render_widget(search_paragraph, main_layout[1]);
     } else {
         // Render file contents as before
-        let contents = if let Some(contents) = &app.file_contents {
-            let lines: Vec<_> = contents
+        let shown_contents = if app.hex_view { &app.hex_contents } else { &app.file_contents };
+        let contents = if let Some(contents) = shown_contents {
+            // Hex rows carry their own offsets, so the line-number gutter is for text only
+            let show_numbers = app.line_numbers && !app.hex_view;
+            let gutter_width = contents.lines().count().max(1).to_string().len();
+            // Syntax colors only apply to text, hex rows are always plain
+            let highlighted = app.highlighted.as_ref().filter(|_| !app.hex_view);
+            let lines: Vec<Line> = contents
                 .lines()
+                .enumerate()
                 .skip(app.scroll_offset as usize)
-                .take(f.size().height as usize)
-                .map(String::from)
+                .take(main_layout[1].height as usize)
+                .map(|(i, line)| {
+                    let mut spans = match highlighted.and_then(|lines| lines.get(i)) {
+                        Some(colored) => colored.spans.clone(),
+                        None => vec![Span::raw(line)],
+                    };
+                    if show_numbers {
+                        spans.insert(
+                            0,
+                            Span::styled(
+                                format!("{:>width$} ", i + 1, width = gutter_width),
+                                Style::default().add_modifier(Modifier::DIM),
+                            ),
+                        );
+                    }
+                    let line = Line::from(spans);
+                    // Lines found by the last search stand out with a background
+                    if !app.hex_view && app.match_lines.binary_search(&(i + 1)).is_ok() {
+                        line.style(Style::default().bg(Color::Indexed(238)))
+                    } else {
+                        line
+                    }
+                })
                 .collect();
-            let display_contents = lines.join("\n");
-            Paragraph::new(display_contents)
-                .block(contents_block)
+            let paragraph = Paragraph::new(lines).block(contents_block);
+            if app.wrap { paragraph.wrap(Wrap { trim: false }) } else { paragraph }
         } else {
             Paragraph::new("Select a file to view contents")
                 .block(contents_block)
@@ -269,13 +1340,316 @@ fn ui(f: &mut Frame, app: &mut App) {
         f.render_widget(contents, main_layout[1]);
     }
 
-    // Render third panel if active
+    // Render search results if the third panel is active
     if app.show_third_panel {
-        let third_block = Block::default()
-            .title(" Third Panel ")
-            .borders(Borders::ALL);
-        let third_content = Paragraph::new("This is the third panel (currently empty)")
-            .block(third_block);
-        f.render_widget(third_content, main_layout[2]);
+        let results_block = panel_block(" Search Results ", app.focus == Focus::Search);
+        let hint = if app.file_contents.is_none() {
+            Some("Select a file to search")
+        } else if app.search_input.is_empty() {
+            Some("Type a query and press Enter")
+        } else {
+            match &app.search_results {
+                None => Some("Press Enter to search"),
+                Some(results) if results.is_empty() => Some("No matches"),
+                Some(_) => None,
+            }
+        };
+
+        if let (None, Some(results)) = (hint, &app.search_results) {
+            let items: Vec<ListItem> = results.iter().map(result_item).collect();
+            f.render_widget(List::new(items).block(results_block), main_layout[2]);
+        } else {
+            let hint = Paragraph::new(hint.unwrap_or_default()).block(results_block);
+            f.render_widget(hint, main_layout[2]);
+        }
+    }
+
+    if app.show_finder {
+        render_finder(f, app);
+    }
+    if app.show_help {
+        render_help(f);
+    }
+}
+
+// A bordered panel, its border colored while it has focus
+fn panel_block(title: &str, focused: bool) -> Block<'_> {
+    let block = Block::default().title(title).borders(Borders::ALL);
+    if focused { block.border_style(Style::default().fg(Color::Cyan)) } else { block }
+}
+
+// The selected node's full path, its kind and for files its size, then how many lines match
+// the search, or a recent status message
+fn status_line(app: &App) -> String {
+    if let Some((message, _)) = app.status_message.as_ref().filter(|(_, shown)| shown.elapsed() < STATUS_MESSAGE_TIME) {
+        return format!(" {}", message);
+    }
+    let Some(node) = app.tree_nodes.get(app.selected_index) else {
+        return " Empty directory".to_string();
+    };
+    let path = std::path::absolute(&node.path).unwrap_or_else(|_| node.path.clone());
+    let details = match (&node.error, node.is_dir) {
+        (Some(err), _) => err.clone(),
+        (None, true) => "directory".to_string(),
+        (None, false) => match fs::metadata(&node.path) {
+            Ok(metadata) => format!("file, {} bytes", metadata.len()),
+            Err(_) => "file".to_string(),
+        },
+    };
+    format!(" {}  ({})  {}", path.display(), details, match_count(app))
+}
+
+// How many of the selected file's lines match the search, a dash while no search is active
+fn match_count(app: &App) -> String {
+    match (&app.search_results, &app.file_contents) {
+        (Some(_), Some(contents)) => format!("{}/{} lines match", app.match_lines.len(), contents.lines().count()),
+        _ => "—".to_string(),
+    }
+}
+
+// A width x height rect centered in area, shrunk to fit
+fn centered_rect(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+// Draws the finder popup: the query on top and the ranked paths below, the selected one reversed
+fn render_finder(f: &mut Frame, app: &App) {
+    let area = f.area();
+    let popup = centered_rect(area, area.width * 3 / 5, area.height * 3 / 5);
+    let listing = if app.finder_walk.is_some() { ", listing…" } else { "" };
+    let title = format!(" Find File (Enter to jump, Esc to close{listing}) ");
+    let block = Block::default().title(title).borders(Borders::ALL);
+    let inner = block.inner(popup);
+    f.render_widget(Clear, popup);
+    f.render_widget(block, popup);
+
+    let [query_area, results_area] =
+        Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner);
+    f.render_widget(Paragraph::new(format!("> {}", app.finder_query)), query_area);
+
+    // Only the rows that fit are turned into list items, the list scrolls to keep the selection shown
+    let shown = app.finder_selected + results_area.height as usize;
+    let items: Vec<ListItem> = app.finder_ranked[..shown.min(app.finder_ranked.len())]
+        .iter()
+        .map(|&index| {
+            let path = &app.finder_paths[index];
+            ListItem::new(path.strip_prefix(&app.root).unwrap_or(path).to_string_lossy().into_owned())
+        })
+        .collect();
+    let list = List::new(items).highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected((!app.finder_ranked.is_empty()).then_some(app.finder_selected));
+    f.render_stateful_widget(list, results_area, &mut state);
+}
+
+// Scores path against query as a case-insensitive subsequence, None when it isn't one.
+// Runs of consecutive characters and matches at the start of a name score higher.
+fn fuzzy_score(query: &str, path: &str) -> Option<i64> {
+    let mut candidates = path.chars();
+    let mut previous: Option<char> = None;
+    let mut score = 0;
+
+    for wanted in query.chars() {
+        let mut consecutive = score > 0; // Right after the previous match
+        loop {
+            let c = candidates.next()?;
+            let at_boundary = previous.is_none_or(|p| matches!(p, '/' | '\\' | '_' | '-' | '.' | ' '));
+            previous = Some(c);
+            if c.to_lowercase().eq(wanted.to_lowercase()) {
+                score += 1 + if consecutive { 4 } else { 0 } + if at_boundary { 3 } else { 0 };
+                break;
+            }
+            consecutive = false;
+        }
+    }
+
+    Some(score)
+}
+
+// Draws the keybinding list in a box centered over everything else
+fn render_help(f: &mut Frame) {
+    let key_width = KEYBINDINGS.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let lines: Vec<Line> = KEYBINDINGS
+        .iter()
+        .map(|(key, action)| {
+            Line::from(vec![
+                Span::styled(format!("{:<width$}  ", key, width = key_width), Style::default().fg(Color::Yellow)),
+                Span::raw(*action),
+            ])
+        })
+        .collect();
+
+    let width = (KEYBINDINGS.iter().map(|(_, action)| action.len()).max().unwrap_or(0) + key_width + 6) as u16;
+    let height = KEYBINDINGS.len() as u16 + 2; // Plus the borders
+    let popup = centered_rect(f.area(), width, height);
+
+    let help = Paragraph::new(lines).block(Block::default().title(" Keys (? or Esc to close) ").borders(Borders::ALL));
+    f.render_widget(Clear, popup);
+    f.render_widget(help, popup);
+}
+
+// A search result row: the line number, then the line with every match highlighted
+fn result_item(result: &minigrep::Match) -> ListItem<'_> {
+    let mut spans = vec![Span::styled(
+        format!("{}: ", result.line_number),
+        Style::default().add_modifier(Modifier::DIM),
+    )];
+    let mut last = 0;
+    for &(start, end) in &result.ranges {
+        spans.push(Span::raw(&result.line[last..start]));
+        spans.push(Span::styled(
+            &result.line[start..end],
+            Style::default().fg(Color::Red).add_modifier(Modifier::UNDERLINED),
+        ));
+        last = end;
+    }
+    spans.push(Span::raw(&result.line[last..]));
+    ListItem::new(Line::from(spans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("miniGrep").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    // A directory of its own for each test's files, emptied first
+    fn fixture_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("minigrep-tui-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn flags_go_to_the_cli() {
+        assert!(wants_cli(&args(&["-q", "foo"])));
+        assert!(wants_cli(&args(&["-q=foo"])));
+        assert!(wants_cli(&args(&["-p", "file.txt"]))); // Reported as a missing query
+        assert!(wants_cli(&args(&["-i", "src"])));
+        assert!(wants_cli(&args(&["--icons", "-r"])));
+    }
+
+    #[test]
+    fn browser_arguments_open_the_browser() {
+        assert!(!wants_cli(&args(&[])));
+        assert!(!wants_cli(&args(&["src"])));
+        assert!(!wants_cli(&args(&["--icons", "/var/log"])));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let found = |matches| minigrep::Outcome { matches, had_errors: false };
+        assert_eq!(match_exit_code(found(0), false), 1);
+        assert_eq!(match_exit_code(found(7), false), 0);
+        assert_eq!(match_exit_code(found(0), true), 0);
+        assert_eq!(match_exit_code(found(7), true), 7);
+        assert_eq!(match_exit_code(found(255), true), 255);
+        assert_eq!(match_exit_code(found(1000), true), 255);
+        // A file that couldn't be read wins over what the others matched
+        let failed = minigrep::Outcome { matches: 7, had_errors: true };
+        assert_eq!(match_exit_code(failed, false), 2);
+        assert_eq!(match_exit_code(failed, true), 2);
+    }
+
+    #[test]
+    fn prune_drops_dirs_emptied_by_filtering() {
+        let root = fixture_dir("prune");
+        fs::create_dir_all(root.join("hidden_only")).unwrap();
+        fs::write(root.join("hidden_only/.secret"), "").unwrap();
+        fs::create_dir_all(root.join("outer/empty")).unwrap();
+        fs::create_dir_all(root.join("collapsed")).unwrap();
+        fs::create_dir_all(root.join("kept")).unwrap();
+        fs::write(root.join("kept/file.txt"), "").unwrap();
+
+        let expanded: HashSet<PathBuf> = ["hidden_only", "outer", "outer/empty", "kept"].map(|dir| root.join(dir)).into();
+        let nodes = App::build_tree(&root, 0, false, false, &expanded).unwrap();
+        let shown: Vec<_> = App::prune_empty_dirs(nodes, &expanded)
+            .into_iter()
+            .map(|node| node.path.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        // Hidden files are filtered out, so hidden_only goes, and outer with its empty child
+        assert_eq!(shown, ["collapsed", "kept", "kept/file.txt"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn finder_lists_paths_in_the_background() {
+        let root = fixture_dir("finder");
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+        fs::write(root.join("src/deep/main.rs"), "").unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+
+        let mut app = App::new(root.clone());
+        app.open_finder();
+        while app.finder_walk.is_some() {
+            app.receive_finder_paths();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(app.finder_ranked.len(), 4); // notes.txt, src, src/deep and src/deep/main.rs
+
+        app.finder_query = "main".to_string();
+        app.rank_finder();
+        assert_eq!(app.finder_ranked.len(), 1);
+        app.open_finder_result();
+        assert!(!app.show_finder);
+        assert_eq!(app.tree_nodes[app.selected_index].path, root.join("src/deep/main.rs"));
+    }
+
+    #[test]
+    fn shorter_file_clamps_the_scroll() {
+        let root = fixture_dir("max-scroll");
+        let long: String = (1..=1000).map(|n| format!("line {n}\n")).collect();
+        fs::write(root.join("a_long.txt"), long).unwrap();
+        fs::write(root.join("b_short.txt"), "one\ntwo\nthree\n").unwrap();
+
+        let mut app = App::new(root);
+        app.contents_height = 10;
+        app.read_file_contents();
+        app.scroll_offset = app.max_scroll();
+        assert_eq!(app.scroll_offset, 990);
+
+        app.selected_index = 1;
+        app.read_file_contents();
+        assert_eq!(app.max_scroll(), 0);
+        assert_eq!(app.scroll_offset, 0);
+    }
+
+    #[test]
+    fn status_bar_counts_matching_lines() {
+        let root = fixture_dir("match-count");
+        fs::write(root.join("notes.txt"), "foo\nbar\nfoo again\n").unwrap();
+
+        let mut app = App::new(root);
+        app.read_file_contents();
+        assert!(status_line(&app).ends_with("  —"));
+
+        app.search_input = "foo".to_string();
+        app.run_search();
+        assert!(status_line(&app).ends_with("  2/3 lines match"));
+
+        app.search_input = "missing".to_string();
+        app.run_search();
+        assert!(status_line(&app).ends_with("  0/3 lines match"));
+    }
+
+    #[test]
+    fn matching_lines_are_joined_for_the_clipboard() {
+        let root = fixture_dir("yank-matches");
+        fs::write(root.join("notes.txt"), "foo\nbar\nfoo again\n").unwrap();
+
+        let mut app = App::new(root);
+        app.read_file_contents();
+        assert_eq!(app.matching_text(), None);
+
+        app.search_input = "foo".to_string();
+        app.run_search();
+        assert_eq!(app.matching_text().as_deref(), Some("foo\nfoo again"));
+
+        app.search_input = "missing".to_string();
+        app.run_search();
+        assert_eq!(app.matching_text(), None);
     }
 }
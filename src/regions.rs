@@ -0,0 +1,229 @@
+// Lightweight tokenizer that splits source lines into code, comment and string regions,
+// used by --in-comments and --in-strings and by the browser's syntax highlighting. It only
+// knows about comment markers, quotes and keywords, so it is a heuristic rather than a real parser.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Code,
+    Comment,
+    Str,
+}
+
+pub struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    char_literals: bool, // 'x' and '\n' are literals, while a lone ' (a lifetime) is code
+    keywords: &'static [&'static str],
+}
+
+impl Syntax {
+    pub fn keywords(&self) -> &'static [&'static str] {
+        self.keywords
+    }
+}
+
+const RUST: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"'], // Single quotes are lifetimes as often as char literals, see char_literals
+    char_literals: true,
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "false", "fn",
+        "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+        "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+        "while",
+    ],
+};
+
+const C_LIKE: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+    char_literals: false,
+    keywords: &[
+        "break", "case", "catch", "char", "class", "const", "continue", "default", "delete", "do", "double",
+        "else", "enum", "extends", "false", "finally", "float", "for", "func", "function", "if", "implements",
+        "import", "int", "interface", "let", "long", "new", "null", "package", "private", "protected",
+        "public", "return", "static", "struct", "switch", "this", "throw", "true", "try", "typedef", "var",
+        "void", "while",
+    ],
+};
+
+const HASH: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+    char_literals: false,
+    keywords: &[
+        "and", "as", "break", "case", "class", "continue", "def", "do", "done", "elif", "else", "end", "esac",
+        "except", "false", "False", "fi", "finally", "for", "from", "function", "if", "import", "in", "is",
+        "lambda", "local", "None", "not", "or", "pass", "raise", "return", "then", "true", "True", "try",
+        "while", "with", "yield",
+    ],
+};
+
+// Picks the syntax from the file extension, None for languages we don't recognize
+pub fn syntax_for(path: &str) -> Option<&'static Syntax> {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext)?;
+    match extension {
+        "rs" => Some(&RUST),
+        "c" | "h" | "cc" | "cpp" | "hpp" | "java" | "js" | "ts" | "go" | "cs" | "kt" | "swift"
+        | "scala" => Some(&C_LIKE),
+        "py" | "sh" | "bash" | "rb" | "pl" | "toml" | "yaml" | "yml" | "r" => Some(&HASH),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Code,
+    BlockComment,
+    Str(char),
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().next().map(char::len_utf8).unwrap_or(1)
+}
+
+// The length of the char literal rest starts with, like 'x', '\'' or '\u{1F600}',
+// None when the quote is a lifetime instead
+fn char_literal_len(rest: &str) -> Option<usize> {
+    let body = rest.strip_prefix('\'')?;
+    let inner = if let Some(escape) = body.strip_prefix('\\') {
+        // The escaped character may be a quote itself, so look for the closing one after it
+        let escaped = char_len(escape);
+        let close = escape.get(escaped..)?.find('\'')?;
+        1 + escaped + close
+    } else {
+        char_len(body)
+    };
+    // \u{10FFFF} is the longest escape
+    (inner <= 10 && body.get(inner..)?.starts_with('\'')).then_some(inner + 2)
+}
+
+// Splits every line of contents into (region, text) segments. Block comments and
+// unterminated strings carry over to the following lines.
+pub fn split_regions<'a>(contents: &'a str, syntax: &Syntax) -> Vec<Vec<(Region, &'a str)>> {
+    let mut lines = Vec::new();
+    let mut state = State::Code;
+
+    for line in contents.lines() {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+
+        while i < line.len() {
+            let rest = &line[i..];
+            match state {
+                State::Code => {
+                    if syntax.line_comments.iter().any(|marker| rest.starts_with(marker)) {
+                        segments.push((Region::Code, &line[start..i]));
+                        segments.push((Region::Comment, &line[i..]));
+                        start = line.len();
+                        break;
+                    } else if let Some((open, _)) = syntax.block_comment.filter(|(open, _)| rest.starts_with(open)) {
+                        segments.push((Region::Code, &line[start..i]));
+                        start = i;
+                        i += open.len();
+                        state = State::BlockComment;
+                    } else if let Some(len) = char_literal_len(rest).filter(|_| syntax.char_literals) {
+                        segments.push((Region::Code, &line[start..i]));
+                        segments.push((Region::Str, &line[i..i + len]));
+                        i += len;
+                        start = i;
+                    } else if let Some(&quote) = syntax.quotes.iter().find(|&&quote| rest.starts_with(quote)) {
+                        segments.push((Region::Code, &line[start..i]));
+                        start = i;
+                        i += 1;
+                        state = State::Str(quote);
+                    } else {
+                        i += char_len(rest);
+                    }
+                }
+                State::BlockComment => {
+                    let close = syntax.block_comment.map(|(_, close)| close).unwrap_or("*/");
+                    if rest.starts_with(close) {
+                        i += close.len();
+                        segments.push((Region::Comment, &line[start..i]));
+                        start = i;
+                        state = State::Code;
+                    } else {
+                        i += char_len(rest);
+                    }
+                }
+                State::Str(quote) => {
+                    if rest.starts_with('\\') {
+                        i += 1;
+                        if i < line.len() {
+                            i += char_len(&line[i..]);
+                        }
+                    } else if rest.starts_with(quote) {
+                        i += 1;
+                        segments.push((Region::Str, &line[start..i]));
+                        start = i;
+                        state = State::Code;
+                    } else {
+                        i += char_len(rest);
+                    }
+                }
+            }
+        }
+
+        let region = match state {
+            State::Code => Region::Code,
+            State::BlockComment => Region::Comment,
+            State::Str(_) => Region::Str,
+        };
+        if start < line.len() {
+            segments.push((region, &line[start..]));
+        }
+        lines.push(segments);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Without the empty segments left where a region starts the line
+    fn regions_of(contents: &str) -> Vec<Vec<(Region, &str)>> {
+        split_regions(contents, syntax_for("test.rs").unwrap())
+            .into_iter()
+            .map(|segments| segments.into_iter().filter(|(_, text)| !text.is_empty()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn line_comments() {
+        let lines = regions_of("let x = 1; // foo here");
+        assert_eq!(lines[0], vec![(Region::Code, "let x = 1; "), (Region::Comment, "// foo here")]);
+    }
+
+    #[test]
+    fn quote_char_literal_doesnt_open_a_string() {
+        let lines = regions_of("let c = '\"';\n// foo here");
+        assert_eq!(lines[0], vec![(Region::Code, "let c = "), (Region::Str, "'\"'"), (Region::Code, ";")]);
+        assert_eq!(lines[1], vec![(Region::Comment, "// foo here")]);
+    }
+
+    #[test]
+    fn escaped_char_literals() {
+        for literal in ["'\\''", "'\\n'", "'\\u{1F600}'", "'é'"] {
+            let line = format!("f({}); // foo", literal);
+            let lines = regions_of(&line);
+            assert_eq!(lines[0][1], (Region::Str, literal), "{}", literal);
+            assert_eq!(lines[0].last(), Some(&(Region::Comment, "// foo")), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn lifetimes_stay_code() {
+        let lines = regions_of("fn f<'a>(x: &'a str) -> &'static str { x } // foo");
+        assert_eq!(lines[0][0].0, Region::Code);
+        assert!(!lines[0].iter().any(|(region, _)| *region == Region::Str));
+        assert_eq!(lines[0].last(), Some(&(Region::Comment, "// foo")));
+    }
+}
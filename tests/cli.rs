@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn minigrep(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_miniGrep")).args(args).output().unwrap()
+}
+
+// A file of its own for each test, so tests can run at the same time
+fn fixture(test: &str, contents: &str) -> String {
+    let path: PathBuf = std::env::temp_dir().join(format!("minigrep-cli-{}-{}.txt", std::process::id(), test));
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn exits_0_on_a_match_and_1_without_one() {
+    let path = fixture("exit", "foo\nbar\n");
+    let found = minigrep(&["-q", "foo", "-p", &path]);
+    assert_eq!(found.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&found.stdout).contains("foo"));
+
+    let missing = minigrep(&["-q", "baz", "-p", &path]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(missing.stdout.is_empty());
+}
+
+#[test]
+fn count_exit_uses_the_match_count() {
+    let path = fixture("count-exit", &"foo\n".repeat(300));
+    assert_eq!(minigrep(&["-q", "foo", "-p", &path, "-m", "7", "--count-exit"]).status.code(), Some(7));
+    assert_eq!(minigrep(&["-q", "foo", "-p", &path, "--count-exit"]).status.code(), Some(255));
+    assert_eq!(minigrep(&["-q", "baz", "-p", &path, "--count-exit"]).status.code(), Some(0));
+}
+
+#[test]
+fn help_prints_usage() {
+    let help = minigrep(&["--help"]);
+    assert_eq!(help.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&help.stdout).starts_with("Usage:"));
+}
+
+#[test]
+fn bad_arguments_exit_2() {
+    let unknown = minigrep(&["-q", "foo", "--foo"]);
+    assert_eq!(unknown.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("unknown flag: --foo"));
+
+    // A file without -p is an error rather than a search of standard input
+    let stray = minigrep(&["-q", "foo", "notes.txt"]);
+    assert_eq!(stray.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&stray.stderr).contains("unexpected argument: notes.txt"));
+
+    let unreadable = minigrep(&["-q", "foo", "-p", "/nonexistent/minigrep.txt"]);
+    assert_eq!(unreadable.status.code(), Some(2));
+
+    // The other files are still searched, but the run as a whole failed
+    let path = fixture("missing-one", "foo\n");
+    let partly = minigrep(&["-q", "foo", "-p", &path, "/nonexistent/minigrep.txt"]);
+    assert_eq!(partly.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&partly.stdout).contains("foo"));
+    assert!(String::from_utf8_lossy(&partly.stderr).contains("/nonexistent/minigrep.txt"));
+
+    assert_eq!(minigrep(&["-q", "foo", "-r", "-p", "/nonexistent/minigrep"]).status.code(), Some(2));
+}
+
+#[test]
+fn validate_regex_only_compiles_the_query() {
+    // The file doesn't exist, which doesn't matter since it isn't read
+    let valid = minigrep(&["-q", "^fo+$", "-p", "/nonexistent/minigrep.txt", "--validate-regex"]);
+    assert_eq!(valid.status.code(), Some(0));
+    assert!(valid.stdout.is_empty() && valid.stderr.is_empty());
+
+    let broken = minigrep(&["-q", "fo[o", "-p", "/nonexistent/minigrep.txt", "--validate-regex"]);
+    assert_eq!(broken.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&broken.stderr);
+    // The regex error points at where the pattern goes wrong
+    assert!(stderr.contains("fo[o\n") && stderr.contains("^"), "{stderr}");
+}
+
+#[test]
+fn stats_go_to_stderr() {
+    let matching = fixture("stats-a", "foo\nfoo\n");
+    let other = fixture("stats-b", "bar\n");
+    let output = minigrep(&["-q", "foo", "-p", &matching, &other, "--stats"]);
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "2 matches in 1 files (searched 2)\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).lines().count(), 2);
+}
+
+#[test]
+fn stats_with_limits() {
+    let matching = fixture("stats-limits-a", "foo\nfoo\nfoo\n");
+    let other = fixture("stats-limits-b", "bar\n");
+    let stats = |extra: &[&str]| {
+        let output = minigrep(&[&["-q", "foo", "-p", &matching, &other, "--stats"], extra].concat());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    // Listing stops at a file's first match, so only the files are counted
+    assert_eq!(stats(&["-l"]), "1 files matched (searched 2)\n");
+    assert_eq!(stats(&["-L"]), "1 files matched (searched 2)\n");
+    // -m counts the matches it printed
+    assert_eq!(stats(&["-m", "2"]), "2 matches in 1 files (searched 2)\n");
+    assert_eq!(stats(&["-c"]), "3 matches in 1 files (searched 2)\n");
+}
+
+#[test]
+fn density_sorts_files_by_matches_per_kib() {
+    // Both have two matches, but the first is 1 KiB and the second only 8 bytes
+    let large = fixture("density-large", &format!("foo\nfoo\n{}", "bar\n".repeat(254)));
+    let small = fixture("density-small", "foo\nfoo\n");
+    let none = fixture("density-none", "bar\n");
+    let output = minigrep(&["-q", "foo", "-p", &large, &small, &none, "--stats", "--density"]);
+    let expected = format!("4 matches in 2 files (searched 3)\n256.00 matches/KiB  {small}\n2.00 matches/KiB  {large}\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), expected);
+}
+
+#[test]
+fn time_budget_notes_partial_results() {
+    let path = fixture("time-budget", "foo\n");
+    let stopped = minigrep(&["-q", "foo", "-p", &path, &path, "--time-budget", "0s"]);
+    assert!(stopped.stdout.is_empty());
+    assert_eq!(String::from_utf8_lossy(&stopped.stderr), "time budget exceeded, the results are partial\n");
+
+    let finished = minigrep(&["-q", "foo", "-p", &path, &path, "--time-budget", "1m"]);
+    assert_eq!(String::from_utf8_lossy(&finished.stdout).lines().count(), 2);
+    assert!(finished.stderr.is_empty());
+}